@@ -13,6 +13,9 @@ usage = Usage
 
 # Commands
 command-syslog = Run in syslog mode
+command-archive = Query the SQLite message archive
+command-archive-search = Search archived messages
+command-archive-export = Export archived messages as CSV or JSON
 
 # Arguments
 arg-bot-token = Telegram bot token
@@ -24,9 +27,30 @@ arg-parse-mode = Telegram message parse mode
 arg-syslog-host = Syslog server host
 arg-syslog-port = Syslog server port
 arg-webhook-url = Webhook URL for forwarding messages (optional, in addition to or instead of Telegram)
+arg-webhook-secret = Shared secret for signing webhook requests (HMAC-SHA256, X-EMtT-Signature header)
+arg-webhook-format = Webhook payload format: v1 (message fields only), v2 (versioned envelope) or CloudEvents
+arg-webhook-retries = Number of retries for failed webhook requests
+arg-webhook-timeout = Webhook request timeout in seconds
 arg-proxy = Proxy URL for outgoing connections (supports http://, https://, socks5:// and socks5h:// proxies)
 arg-api-server = Telegram Bot API (optional; default = official api.telegram.org; for self-hosted use http://127.0.0.1:8081)
 arg-log-level = Log level
+arg-edit-duplicates = Edit the forwarded Telegram message when the same packet is heard by another gateway or with better SNR
+arg-reply-threading = Send Meshtastic replies as Telegram replies and tapbacks as reactions
+arg-inline-keyboard = Add "Node info" and "Signal history" buttons to forwarded messages
+arg-bot-commands = Answer the /gateways command in the configured chats
+arg-config = Path to the TOML configuration file with notification and alert rules
+arg-archive-db = Path to the SQLite database for archiving received text messages
+arg-http-listen = Address for the HTTP server with Prometheus metrics at /metrics and health checks at /healthz and /readyz, e.g. 0.0.0.0:9090
+arg-db = Path to the SQLite message archive
+arg-from = Sender: node ID (!aabbccdd) or part of the name
+arg-text = Part of the message text
+arg-via = Gateway that received the message
+arg-filter-channel = Channel index
+arg-since = Start time: YYYY-MM-DD, "YYYY-MM-DD HH:MM", RFC 3339 or an age such as 30m, 2h, 7d
+arg-until = End time, in the same formats as --since
+arg-limit = Show at most this many latest messages
+arg-export-format = Export format
+arg-output = Write to this file instead of standard output
 
 # Boolean values
 true-value = yes
@@ -36,6 +60,12 @@ false-value = no
 parse-mode-none = Plain text
 parse-mode-html = HTML
 parse-mode-markdown = Markdown
+payload-format-v1 = Message fields only, as in EMtT 1.x
+payload-format-v2 = Versioned envelope with node IDs, channel, packet ID and gateways
+payload-format-cloudevents = CloudEvents 1.0, structured mode (text, nodeinfo and telemetry events)
+payload-format-cloudevents-binary = CloudEvents 1.0, binary mode with ce-* headers (text, nodeinfo and telemetry events)
+export-format-csv = Comma-separated values with a header row
+export-format-json = JSON array of messages
 
 # Log levels (used in --help)
 log-level-error = Error
@@ -76,10 +106,52 @@ forwarded-to-telegram = Message forwarded to Telegram (from { $from }):
   { $message }
 failed-to-render = Failed to render template: { $error }
 failed-to-send = Failed to send message to Telegram: { $error }
+failed-to-edit = Failed to edit message in Telegram: { $error }
 failed-to-send-webhook = Failed to send message to webhook: { $error }
+forwarded-to-discord = Message forwarded to Discord (from { $from }):
+  { $message }
+failed-to-send-discord = Failed to send message to Discord: { $error }
+forwarded-to-matrix = Message forwarded to Matrix room { $room } (from { $from }):
+  { $message }
+failed-to-send-matrix = Failed to send message to Matrix room { $room }: { $error }
+forwarded-to-push = Message pushed to { $service } (from { $from }):
+  { $message }
+failed-to-send-push = Failed to push message to { $service }: { $error }
+forwarded-to-email = Email with { $count } message(s) sent:
+  { $message }
+failed-to-send-email = Failed to send email: { $error }
+email-queue-full = Email queue is full, dropping message from { $from }
+exec-finished = Command { $command } finished for message from { $from }:
+  { $output }
+exec-failed = Command { $command } exited with code { $code }:
+  { $output }
+failed-to-exec = Failed to run command { $command }: { $error }
+exec-timed-out = Command { $command } killed after timeout of { $timeout } s
+archive-queue-full = Archive queue is full, dropping { $event } event
+database-queue-full = Database queue is full, dropping { $event } event
+failed-to-write-database = Failed to write to the message database: { $error }
+failed-to-write-archive = Failed to write to the archive: { $error }
+archive-rotated = Archive rotated to { $path }
+archive-compressed = Rotated archive compressed to { $path }
+failed-to-compress-archive = Failed to compress rotated archive { $path }: { $error }
+published-to-mqtt = Published to MQTT topic { $topic }:
+  { $message }
+failed-to-publish-mqtt = Failed to publish to MQTT topic { $topic }: { $error }
+mqtt-queue-full = the outgoing queue is full, the broker is unreachable
+mqtt-connected = Connected to MQTT broker { $broker }
+mqtt-connection-error = MQTT broker { $broker } connection error: { $error }
+mqtt-shutdown = Shutdown signal received, disconnecting from MQTT broker
+email-shutdown = Shutdown signal received, email batching stopped
+archive-shutdown = Shutdown signal received, archive closed
+database-shutdown = Shutdown signal received, message database closed
+failed-to-sign-webhook = Failed to sign webhook request: { $error }
+http-retrying = Request failed: { $error }. Retry { $attempt } of { $retries } in { $delay } s
 message-content = Message content: { $content }
 processed-nodeinfo = Processed node info: { $longname } ({ $shortname }) - { $id }
+processed-telemetry = Processed telemetry via { $via }: { $metrics }
 syslog-binding = Syslog server listening on { $addr }
+http-server-binding = HTTP server listening on { $addr }
+http-server-shutdown = HTTP server stopped
 received-text-msg = Received text message from { $from } (ID: { $id }):
   { $text }
 recv-error = Receive error: { $error }
@@ -88,9 +160,21 @@ failed-to-parse-syslog = Failed to parse syslog message: { $error }, raw data: {
 unhandled-syslog = Unhandled syslog message: { $message }
 webhook-enabled = Webhook forwarding enabled to: { $url }
 webhook-disabled = Webhook forwarding disabled
+webhook-retry-policy = Webhook retries: { $retries }, timeout: { $timeout } s
+discord-enabled = Discord forwarding enabled, routes: { $count }
+matrix-enabled = Matrix forwarding enabled, rooms: { $count }
+mqtt-enabled = MQTT publishing enabled, broker: { $broker }
+homeassistant-enabled = Home Assistant MQTT discovery enabled
+push-enabled = Push notifications enabled (ntfy, Gotify), routes: { $count }
+email-enabled = Email forwarding enabled, recipients: { $count }
+exec-enabled = Command hooks enabled: { $count }
+archive-enabled = Archiving all events to { $path }
+database-enabled = Archiving text messages to database { $path }
+webhook-signing = Webhook request signing: { $enabled }
+webhook-format = Webhook format: { $format }
 forwarded-to-webhook = Message forwarded to webhook (from { $from }):
   { $message }
-no-output-configured = At least one output (Telegram or webhook) must be configured
+no-output-configured = At least one output (Telegram, webhook or a sink from the config file) must be configured
 proxy-enabled = Proxy enabled: { $url }
 invalid-proxy-url = Invalid proxy URL '{ $url }': { $error }
 http-client-build-error = Failed to build HTTP client: { $error }
@@ -102,3 +186,76 @@ cleaned-stale-handle-info = Cleaned up stale handle info for id: { $id }
 cleanup-shutdown = Cleanup subsystem shutting down
 shutdown-signal-received = Shutdown signal received, stopping syslog server
 press-enter-close = Press Enter to close this window...
+config-loaded = Configuration file loaded: { $path }
+config-load-error = Failed to load configuration file { $path }: { $error }
+invalid-notification-rules = Invalid notification rules: { $error }
+silent-rules-enabled = Silent notification rules: { $count }
+invalid-alert-rules = Invalid alert rules: { $error }
+invalid-webhook = Invalid webhook in the config file: { $error }
+invalid-discord-route = Invalid Discord route in the config file: { $error }
+invalid-matrix-config = Invalid Matrix settings in the config file: { $error }
+invalid-mqtt-config = Invalid MQTT settings in the config file: { $error }
+invalid-push-route = Invalid ntfy or Gotify route in the config file: { $error }
+invalid-email-config = Invalid email settings in the config file: { $error }
+invalid-exec-hook = Invalid command hook in the config file: { $error }
+invalid-archive-config = Invalid archive settings in the config file: { $error }
+invalid-database = Failed to open the message database: { $error }
+archive-query-error = Archive query failed: { $error }
+archive-ignored = not forwarded: { $reason }
+archive-no-results = No messages found
+archive-exported = Exported messages: { $count }
+alerts-require-bot-token = Alert rules with chat IDs require a Telegram bot token
+alert-rules-enabled = Alert rules: { $count }
+alert-triggered = Alert "{ $alert }" triggered by a message from { $from }
+alert-sent = Alert "{ $alert }" sent to chat { $chat_id }
+invalid-gateways-config = Invalid gateways config: { $error }
+gateways-require-bot-token = Gateway silence alarms require a Telegram bot token
+gateway-monitor-enabled = Gateway silence alarm after { $timeout } seconds
+gateway-silent = Gateway { $gateway } went silent
+gateway-recovered = Gateway { $gateway } is back
+gateway-low-battery = Gateway { $gateway } battery is low: { $battery }%
+gateway-busy-channel = Gateway { $gateway } channel utilization is high: { $utilization }%
+gateway-monitor-shutdown = Gateway monitor stopped
+invalid-nodes-config = Invalid nodes config: { $error }
+nodes-require-bot-token = Node notifications require a Telegram bot token
+roster-loaded = Known nodes loaded from { $path }: { $count }
+failed-to-save-roster = Failed to save known nodes: { $error }
+roster-shutdown = Known nodes saved, roster stopped
+node-discovered = New node { $id }: { $longname }
+node-renamed = Node { $id } renamed from "{ $old }" to "{ $new }"
+node-name-conflict = Node { $id } calls itself "{ $longname }", the same name as node { $other }
+node-notification-sent = Notification about node { $id } sent to chat { $chat_id }
+failed-to-pin = Failed to pin message in Telegram: { $error }
+edit-duplicates = Editing messages for duplicate packets: { $enabled }
+edited-in-telegram = Message for packet ID { $id } edited in Telegram with data from { $via }
+duplicate-not-edited = Packet ID { $id } via { $via } has already been forwarded, signal did not improve
+reply-threading = Reply threading: { $enabled }
+reaction-set = Reaction { $reaction } from { $from } set in Telegram
+reaction-fallback = Failed to set reaction, sending as a reply: { $error }
+inline-keyboard = Node info buttons: { $enabled }
+bot-commands = Bot commands: { $enabled }
+callback-received = Button pressed by user { $user }: { $data }
+command-ignored = Ignoring a command from chat { $chat_id }, it isn't in the chat list
+bot-command-gateways = Gateway status
+failed-to-register-commands = Failed to register bot commands in chat { $chat_id }: { $error }
+bot-shutdown = Telegram bot shutting down
+bot-get-me-failed = Failed to connect to Telegram, retrying in 30 seconds: { $error }
+
+# Inline keyboard and answers
+button-node-info = ℹ️ Node info
+button-signal-history = 📶 Signal history
+node-info-name = { $longname } ({ $shortname })
+node-info-no-name = Name unknown yet
+node-info-last-seen = Last heard { $time } via { $via }
+node-unknown = No information about node { $id } yet
+signal-history-title = Signal history of { $name }:
+signal-history-entry = { $time } { $via }: SNR { $snr }, RSSI { $rssi }, hops { $hops }
+signal-history-empty = No signal data for { $name } yet
+gateways-none = No gateways heard yet
+gateway-status = 📡 { $gateway }, last heard { $time }
+gateway-status-silent = ⚠️ { $gateway } is silent, last heard { $time }
+gateway-battery = battery { $level }%
+gateway-external-power = external power
+gateway-channel-utilization = channel utilization { $value }%
+gateway-air-util-tx = air util TX { $value }%
+gateway-uptime = uptime { $hours } h
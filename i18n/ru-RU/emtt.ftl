@@ -13,6 +13,9 @@ usage = Использование
 
 # Команды
 command-syslog = Запуск в режиме syslog
+command-archive = Работа с архивом сообщений SQLite
+command-archive-search = Поиск по архиву сообщений
+command-archive-export = Выгрузка архива сообщений в CSV или JSON
 
 # Аргументы
 arg-bot-token = Токен бота Telegram
@@ -24,9 +27,30 @@ arg-parse-mode = Режим обработки Telegram-сообщений
 arg-syslog-host = Хост сервера syslog
 arg-syslog-port = Порт сервера syslog
 arg-webhook-url = URL вебхука для пересылки сообщений (опционально, в дополнение или вместо Telegram)
+arg-webhook-secret = Общий секрет для подписи запросов к вебхуку (HMAC-SHA256, заголовок X-EMtT-Signature)
+arg-webhook-format = Формат данных для вебхука: v1 (только поля сообщения), v2 (версионированный конверт) или CloudEvents
+arg-webhook-retries = Количество повторов неудачных запросов к вебхуку
+arg-webhook-timeout = Тайм-аут запроса к вебхуку в секундах
 arg-proxy = URL прокси для исходящих соединений (поддерживает http://, https://, socks5:// и socks5h:// прокси)
 arg-api-server = Telegram Bot API (опционально; по умолчанию официальный api.telegram.org; для self-hosted: http://127.0.0.1:8081)
 arg-log-level = Уровень логирования
+arg-edit-duplicates = Редактировать пересланное в Telegram сообщение, когда тот же пакет принят другим шлюзом или с лучшим SNR
+arg-reply-threading = Отправлять ответы из Meshtastic как ответы в Telegram, а реакции — как реакции
+arg-inline-keyboard = Добавлять к пересланным сообщениям кнопки «Об узле» и «История сигнала»
+arg-bot-commands = Отвечать на команду /gateways в настроенных чатах
+arg-config = Путь к TOML-файлу конфигурации с правилами уведомлений и оповещений
+arg-archive-db = Путь к базе SQLite для архива полученных сообщений
+arg-http-listen = Адрес HTTP-сервера с метриками Prometheus на /metrics и проверками на /healthz и /readyz, например 0.0.0.0:9090
+arg-db = Путь к архиву сообщений SQLite
+arg-from = Отправитель: ID узла (!aabbccdd) или часть имени
+arg-text = Часть текста сообщения
+arg-via = Шлюз, принявший сообщение
+arg-filter-channel = Номер канала
+arg-since = Начало периода: ГГГГ-ММ-ДД, "ГГГГ-ММ-ДД ЧЧ:ММ", RFC 3339 или давность вроде 30m, 2h, 7d
+arg-until = Конец периода, в тех же форматах, что и --since
+arg-limit = Показать не больше стольких последних сообщений
+arg-export-format = Формат выгрузки
+arg-output = Записать в файл вместо стандартного вывода
 
 # Булевы значения
 true-value = да
@@ -36,6 +60,12 @@ false-value = нет
 parse-mode-none = Обычный текст
 parse-mode-html = HTML
 parse-mode-markdown = Markdown
+payload-format-v1 = Только поля сообщения, как в EMtT 1.x
+payload-format-v2 = Версионированный конверт с ID узлов, каналом, ID пакета и шлюзами
+payload-format-cloudevents = CloudEvents 1.0, структурированный режим (события text, nodeinfo и telemetry)
+payload-format-cloudevents-binary = CloudEvents 1.0, бинарный режим с заголовками ce-* (события text, nodeinfo и telemetry)
+export-format-csv = Значения через запятую с заголовком
+export-format-json = JSON-массив сообщений
 
 # Уровни логирования (используются в --help)
 log-level-error = Ошибка
@@ -76,10 +106,52 @@ forwarded-to-telegram = Сообщение отправлено в Telegram (о
   { $message }
 failed-to-render = Ошибка рендеринга шаблона: { $error }
 failed-to-send = Ошибка отправки в Telegram: { $error }
+failed-to-edit = Ошибка редактирования сообщения в Telegram: { $error }
 failed-to-send-webhook = Ошибка отправки в вебхук: { $error }
+forwarded-to-discord = Сообщение переслано в Discord (от { $from }):
+  { $message }
+failed-to-send-discord = Не удалось отправить сообщение в Discord: { $error }
+forwarded-to-matrix = Сообщение переслано в комнату Matrix { $room } (от { $from }):
+  { $message }
+failed-to-send-matrix = Не удалось отправить сообщение в комнату Matrix { $room }: { $error }
+forwarded-to-push = Сообщение отправлено в { $service } (от { $from }):
+  { $message }
+failed-to-send-push = Не удалось отправить сообщение в { $service }: { $error }
+forwarded-to-email = Отправлено письмо с сообщениями ({ $count }):
+  { $message }
+failed-to-send-email = Не удалось отправить письмо: { $error }
+email-queue-full = Очередь писем переполнена, сообщение от { $from } пропущено
+exec-finished = Команда { $command } выполнена для сообщения от { $from }:
+  { $output }
+exec-failed = Команда { $command } завершилась с кодом { $code }:
+  { $output }
+failed-to-exec = Не удалось запустить команду { $command }: { $error }
+exec-timed-out = Команда { $command } остановлена по таймауту { $timeout } с
+archive-queue-full = Очередь архива переполнена, событие { $event } пропущено
+database-queue-full = Очередь базы данных переполнена, событие { $event } пропущено
+failed-to-write-database = Не удалось записать в базу сообщений: { $error }
+failed-to-write-archive = Не удалось записать в архив: { $error }
+archive-rotated = Архив перенесён в { $path }
+archive-compressed = Перенесённый архив сжат в { $path }
+failed-to-compress-archive = Не удалось сжать перенесённый архив { $path }: { $error }
+published-to-mqtt = Опубликовано в MQTT-топик { $topic }:
+  { $message }
+failed-to-publish-mqtt = Не удалось опубликовать в MQTT-топик { $topic }: { $error }
+mqtt-queue-full = очередь исходящих сообщений заполнена, брокер недоступен
+mqtt-connected = Подключено к MQTT-брокеру { $broker }
+mqtt-connection-error = Ошибка соединения с MQTT-брокером { $broker }: { $error }
+mqtt-shutdown = Получен сигнал завершения, отключение от MQTT-брокера
+email-shutdown = Получен сигнал завершения, отправка писем остановлена
+archive-shutdown = Получен сигнал завершения, архив закрыт
+database-shutdown = Получен сигнал завершения, база сообщений закрыта
+failed-to-sign-webhook = Не удалось подписать запрос к вебхуку: { $error }
+http-retrying = Запрос не удался: { $error }. Повтор { $attempt } из { $retries } через { $delay } с
 message-content = Содержимое сообщения: { $content }
 processed-nodeinfo = Обработана информация об узле: { $longname } ({ $shortname }) - { $id }
+processed-telemetry = Обработана телеметрия через { $via }: { $metrics }
 syslog-binding = Сервер syslog ожидает подключений на { $addr }
+http-server-binding = HTTP-сервер ожидает подключений на { $addr }
+http-server-shutdown = HTTP-сервер остановлен
 received-text-msg = Получено текстовое сообщение от { $from } (ID: { $id }):
   { $text }
 recv-error = Ошибка получения: { $error }
@@ -88,9 +160,21 @@ failed-to-parse-syslog = Ошибка разбора syslog-сообщения:
 unhandled-syslog = Необработанное syslog-сообщение: { $message }
 webhook-enabled = Пересылка в вебхук включена для: { $url }
 webhook-disabled = Вебхук отключён
+webhook-retry-policy = Повторов запросов к вебхуку: { $retries }, тайм-аут: { $timeout } с
+discord-enabled = Пересылка в Discord включена, маршрутов: { $count }
+matrix-enabled = Пересылка в Matrix включена, комнат: { $count }
+mqtt-enabled = Публикация в MQTT включена, брокер: { $broker }
+homeassistant-enabled = Включено автообнаружение Home Assistant через MQTT
+push-enabled = Push-уведомления включены (ntfy, Gotify), маршрутов: { $count }
+email-enabled = Пересылка по электронной почте включена, получателей: { $count }
+exec-enabled = Внешние команды включены: { $count }
+archive-enabled = Все события записываются в архив { $path }
+database-enabled = Сообщения записываются в базу { $path }
+webhook-signing = Подпись запросов к вебхуку: { $enabled }
+webhook-format = Формат вебхука: { $format }
 forwarded-to-webhook = Сообщение отправлено в вебхук (от { $from }):
   { $message }
-no-output-configured = Настройте пересылку в Telegram или вебхук
+no-output-configured = Настройте пересылку в Telegram, вебхук или другой канал в файле конфигурации
 proxy-enabled = Прокси включён: { $url }
 invalid-proxy-url = Некорректный URL прокси '{ $url }': { $error }
 http-client-build-error = Не удалось создать HTTP-клиент: { $error }
@@ -102,3 +186,76 @@ cleaned-stale-handle-info = Очищены устаревшие метаданн
 cleanup-shutdown = Подсистема очистки завершает работу
 shutdown-signal-received = Получен сигнал завершения, остановка сервера syslog
 press-enter-close = Нажмите Enter, чтобы закрыть окно...
+config-loaded = Загружен файл конфигурации: { $path }
+config-load-error = Не удалось загрузить файл конфигурации { $path }: { $error }
+invalid-notification-rules = Некорректные правила уведомлений: { $error }
+silent-rules-enabled = Правил беззвучных уведомлений: { $count }
+invalid-alert-rules = Некорректные правила оповещений: { $error }
+invalid-webhook = Некорректный вебхук в файле конфигурации: { $error }
+invalid-discord-route = Некорректный маршрут Discord в файле конфигурации: { $error }
+invalid-matrix-config = Некорректные настройки Matrix в файле конфигурации: { $error }
+invalid-mqtt-config = Некорректные настройки MQTT в файле конфигурации: { $error }
+invalid-push-route = Некорректный маршрут ntfy или Gotify в файле конфигурации: { $error }
+invalid-email-config = Некорректные настройки электронной почты в файле конфигурации: { $error }
+invalid-exec-hook = Некорректная внешняя команда в файле конфигурации: { $error }
+invalid-archive-config = Некорректные настройки архива в файле конфигурации: { $error }
+invalid-database = Не удалось открыть базу сообщений: { $error }
+archive-query-error = Ошибка запроса к архиву: { $error }
+archive-ignored = не переслано: { $reason }
+archive-no-results = Сообщения не найдены
+archive-exported = Выгружено сообщений: { $count }
+alerts-require-bot-token = Для правил оповещений с ID чатов нужен токен Telegram-бота
+alert-rules-enabled = Правил оповещений: { $count }
+alert-triggered = Сработало оповещение «{ $alert }» на сообщение от { $from }
+alert-sent = Оповещение «{ $alert }» отправлено в чат { $chat_id }
+invalid-gateways-config = Некорректные настройки шлюзов: { $error }
+gateways-require-bot-token = Для оповещений о молчании шлюзов нужен токен Telegram-бота
+gateway-monitor-enabled = Оповещение о молчании шлюза через { $timeout } с
+gateway-silent = Шлюз { $gateway } замолчал
+gateway-recovered = Шлюз { $gateway } снова на связи
+gateway-low-battery = Низкий заряд батареи шлюза { $gateway }: { $battery }%
+gateway-busy-channel = Высокая загрузка канала у шлюза { $gateway }: { $utilization }%
+gateway-monitor-shutdown = Мониторинг шлюзов остановлен
+invalid-nodes-config = Некорректные настройки узлов: { $error }
+nodes-require-bot-token = Для уведомлений об узлах нужен токен Telegram-бота
+roster-loaded = Известные узлы загружены из { $path }: { $count }
+failed-to-save-roster = Не удалось сохранить известные узлы: { $error }
+roster-shutdown = Известные узлы сохранены, учёт узлов остановлен
+node-discovered = Новый узел { $id }: { $longname }
+node-renamed = Узел { $id } сменил имя с «{ $old }» на «{ $new }»
+node-name-conflict = Узел { $id } называет себя «{ $longname }», так же как узел { $other }
+node-notification-sent = Уведомление об узле { $id } отправлено в чат { $chat_id }
+failed-to-pin = Не удалось закрепить сообщение в Telegram: { $error }
+edit-duplicates = Редактирование сообщений для повторных пакетов: { $enabled }
+edited-in-telegram = Сообщение для пакета с ID { $id } отредактировано в Telegram с данными от { $via }
+duplicate-not-edited = Пакет с ID { $id } через { $via } уже был переслан, сигнал не улучшился
+reply-threading = Ответы и реакции: { $enabled }
+reaction-set = Реакция { $reaction } от { $from } поставлена в Telegram
+reaction-fallback = Не удалось поставить реакцию, отправляю как ответ: { $error }
+inline-keyboard = Кнопки с информацией об узле: { $enabled }
+bot-commands = Команды бота: { $enabled }
+callback-received = Пользователь { $user } нажал кнопку: { $data }
+command-ignored = Команда из чата { $chat_id } проигнорирована, его нет в списке чатов
+bot-command-gateways = Состояние шлюзов
+failed-to-register-commands = Не удалось зарегистрировать команды бота в чате { $chat_id }: { $error }
+bot-shutdown = Telegram-бот завершает работу
+bot-get-me-failed = Не удалось подключиться к Telegram, повтор через 30 секунд: { $error }
+
+# Кнопки и ответы на них
+button-node-info = ℹ️ Об узле
+button-signal-history = 📶 История сигнала
+node-info-name = { $longname } ({ $shortname })
+node-info-no-name = Имя пока неизвестно
+node-info-last-seen = Последний приём { $time } через { $via }
+node-unknown = Об узле { $id } пока нет информации
+signal-history-title = История сигнала { $name }:
+signal-history-entry = { $time } { $via }: SNR { $snr }, RSSI { $rssi }, прыжков { $hops }
+signal-history-empty = Данных о сигнале { $name } пока нет
+gateways-none = Шлюзов пока не слышно
+gateway-status = 📡 { $gateway }, последний раз { $time }
+gateway-status-silent = ⚠️ { $gateway } молчит, последний раз { $time }
+gateway-battery = батарея { $level }%
+gateway-external-power = внешнее питание
+gateway-channel-utilization = загрузка канала { $value }%
+gateway-air-util-tx = передача в эфир { $value }%
+gateway-uptime = работает { $hours } ч
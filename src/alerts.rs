@@ -0,0 +1,300 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use minijinja::context;
+use regex::Regex;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use teloxide::Bot;
+use teloxide::utils::{html, markdown};
+
+use crate::events::Event;
+use crate::monitoring::Health;
+use crate::notifications::keyword_regex;
+use crate::webhook::{Webhook, WebhookDefaults};
+use crate::{MessageData, ParseModeOpt, fl, telegram};
+
+// How many packet IDs to remember so a packet heard by several gateways alerts once
+const FIRED_CAPACITY: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    name: String,
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    patterns: Vec<String>,
+    #[serde(default)]
+    chat_ids: Vec<i64>,
+    #[serde(default)]
+    pin: bool,
+    #[serde(default)]
+    mention: Vec<String>,
+    #[serde(default)]
+    mention_ids: Vec<u64>,
+    webhook_url: Option<String>,
+    template: Option<String>,
+}
+
+#[derive(Clone)]
+struct AlertRule {
+    name: String,
+    matchers: Vec<Regex>,
+    chat_ids: Vec<i64>,
+    pin: bool,
+    mention: Vec<String>,
+    mention_ids: Vec<u64>,
+    webhook: Option<Webhook>,
+    template: Option<String>,
+}
+
+#[derive(Default)]
+struct FiredPackets {
+    ids: HashSet<u32>,
+    order: VecDeque<u32>,
+}
+
+#[derive(Clone, Default)]
+pub struct AlertRules {
+    rules: Vec<AlertRule>,
+    fired: Arc<Mutex<FiredPackets>>,
+}
+
+impl FiredPackets {
+    // False when the packet has already fired its alerts
+    fn insert(&mut self, id: u32) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        while self.order.len() > FIRED_CAPACITY {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+impl AlertRule {
+    fn compile(config: &AlertConfig, webhook_defaults: &WebhookDefaults) -> Result<Self> {
+        let mut matchers = Vec::new();
+
+        if !config.keywords.is_empty() {
+            matchers.push(keyword_regex(&config.keywords)?);
+        }
+
+        for pattern in &config.patterns {
+            matchers.push(
+                Regex::new(pattern)
+                    .with_context(|| format!("Invalid pattern in alert '{}'", config.name))?,
+            );
+        }
+
+        if matchers.is_empty() {
+            anyhow::bail!("Alert '{}' has neither keywords nor patterns", config.name);
+        }
+
+        Ok(Self {
+            name: config.name.clone(),
+            matchers,
+            chat_ids: config.chat_ids.clone(),
+            pin: config.pin,
+            mention: config.mention.clone(),
+            mention_ids: config.mention_ids.clone(),
+            webhook: config
+                .webhook_url
+                .clone()
+                .map(|url| Webhook::new(url, webhook_defaults)),
+            template: config.template.clone().map(crate::unescape_template),
+        })
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        self.matchers.iter().any(|re| re.is_match(text))
+    }
+
+    fn render_mentions(&self, parse_mode: ParseModeOpt) -> String {
+        let usernames = self.mention.iter().map(|m| match parse_mode {
+            ParseModeOpt::None => m.clone(),
+            ParseModeOpt::Html => html::escape(m),
+            ParseModeOpt::Markdown => markdown::escape(m),
+        });
+
+        let ids = self.mention_ids.iter().map(|id| match parse_mode {
+            ParseModeOpt::None => id.to_string(),
+            ParseModeOpt::Html => html::user_mention(teloxide::types::UserId(*id), &id.to_string()),
+            ParseModeOpt::Markdown => {
+                markdown::user_mention(teloxide::types::UserId(*id), &id.to_string())
+            }
+        });
+
+        usernames.chain(ids).collect::<Vec<_>>().join(" ")
+    }
+}
+
+impl AlertRules {
+    // Alert webhooks are signed, formatted and retried the same way as the main one
+    pub fn compile(configs: &[AlertConfig], webhook_defaults: &WebhookDefaults) -> Result<Self> {
+        let rules = configs
+            .iter()
+            .map(|c| AlertRule::compile(c, webhook_defaults))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            rules,
+            fired: Arc::default(),
+        })
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    // Name of the first alert rule the message triggers
+    pub fn matching(&self, text: &str) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|r| r.matches(text))
+            .map(|r| r.name.as_str())
+    }
+
+    pub fn needs_bot(&self) -> bool {
+        self.rules.iter().any(|r| !r.chat_ids.is_empty())
+    }
+
+    // Deliveries are spawned, so a slow chat or webhook doesn't hold up the syslog server
+    pub fn process(
+        &self,
+        data: &MessageData,
+        bot: Option<&Bot>,
+        http_client: &Client,
+        default_template: &str,
+        parse_mode_opt: ParseModeOpt,
+        health: &Health,
+    ) {
+        let mut matching = self
+            .rules
+            .iter()
+            .filter(|r| r.matches(&data.text))
+            .peekable();
+        if matching.peek().is_none() || !self.fired.lock().unwrap().insert(data.id) {
+            return;
+        }
+
+        for rule in matching {
+            info!(
+                "{}",
+                fl!(
+                    "alert-triggered",
+                    alert = rule.name.clone(),
+                    from = data.from.clone()
+                )
+            );
+
+            if let Some(bot) = bot
+                && !rule.chat_ids.is_empty()
+            {
+                let rule = rule.clone();
+                let data = data.clone();
+                let bot = bot.clone();
+                let template = default_template.to_string();
+                let sending = health.outbox_entry();
+                tokio::spawn(async move {
+                    let _sending = sending;
+                    send_alert(&rule, &data, &bot, &template, parse_mode_opt).await;
+                });
+            }
+
+            if let Some(webhook) = rule.webhook.clone() {
+                let http_client = http_client.clone();
+                let event = Event::Text(data.clone());
+                let sending = health.outbox_entry();
+                tokio::spawn(async move {
+                    let _sending = sending;
+                    webhook.send(&http_client, &event).await;
+                });
+            }
+        }
+    }
+}
+
+async fn send_alert(
+    rule: &AlertRule,
+    data: &MessageData,
+    bot: &Bot,
+    default_template: &str,
+    parse_mode_opt: ParseModeOpt,
+) {
+    let env = crate::create_template_env(parse_mode_opt);
+    let template = rule.template.as_deref().unwrap_or(default_template);
+
+    let ctx = context! {
+        alert => rule.name.clone(),
+        from => data.from.clone(),
+        is_name_conflict => data.is_name_conflict,
+        via => data.via.clone(),
+        text => data.text.clone(),
+        snr => data.snr,
+        rssi => data.rssi,
+        hops_away => data.hops_away,
+    };
+
+    let mut rendered = match env.render_str(template, ctx) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("{}", fl!("failed-to-render", error = e.to_string()));
+            return;
+        }
+    };
+
+    let mentions = rule.render_mentions(parse_mode_opt);
+    if !mentions.is_empty() {
+        rendered.push('\n');
+        rendered.push_str(&mentions);
+    }
+
+    for chat_id in &rule.chat_ids {
+        let message_id = match telegram::send_message(
+            bot,
+            *chat_id,
+            &rendered,
+            parse_mode_opt.telegram(),
+            false,
+            None,
+            None,
+        )
+        .await
+        {
+            Ok(id) => id,
+            Err(err) => {
+                warn!(
+                    "{}\n{}",
+                    fl!("failed-to-send", error = err.to_string()),
+                    fl!("message-content", content = rendered.clone())
+                );
+                continue;
+            }
+        };
+
+        debug!(
+            "{}",
+            fl!(
+                "alert-sent",
+                alert = rule.name.clone(),
+                chat_id = chat_id.to_string()
+            )
+        );
+
+        if rule.pin
+            && let Err(err) = telegram::pin_message(bot, *chat_id, message_id).await
+        {
+            warn!("{}", fl!("failed-to-pin", error = err.to_string()));
+        }
+    }
+}
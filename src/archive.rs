@@ -0,0 +1,302 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+use crate::events::Event;
+use crate::fl;
+use crate::payload::CloudEvent;
+
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+// Events waiting to be written, extra ones are dropped
+const QUEUE_CAPACITY: usize = 1000;
+
+// The `[archive]` section of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    path: PathBuf,
+    max_size_mb: Option<u64>,
+    #[serde(default)]
+    daily: bool,
+    #[serde(default)]
+    gzip: bool,
+}
+
+#[derive(Clone)]
+pub struct ArchiveSink {
+    tx: mpsc::Sender<Event>,
+    path: PathBuf,
+}
+
+// Receives events and hands them to the file off the async runtime, runs as a subsystem
+pub struct ArchiveWriter {
+    rx: mpsc::Receiver<Event>,
+    file: ArchiveFile,
+}
+
+// Owns the archive file, only used from blocking threads
+struct ArchiveFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    daily: bool,
+    gzip: bool,
+    file: Option<File>,
+    size: u64,
+    // The UTC day the current file was started on
+    date: NaiveDate,
+}
+
+// archive.jsonl -> archive-20240501-120000.jsonl
+// Rotations within the same second get a counter: archive-20240501-120000-1.jsonl
+fn rotated_path(path: &Path, now: DateTime<Utc>, counter: u32) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut base = format!("{}-{}", stem, now.format("%Y%m%d-%H%M%S"));
+    if counter > 0 {
+        base = format!("{}-{}", base, counter);
+    }
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}", base, ext.to_string_lossy()),
+        None => base,
+    };
+    path.with_file_name(name)
+}
+
+// A rotated file exists either as is or, once compressed, with ".gz" appended
+fn is_taken(path: &Path) -> bool {
+    let mut gz_name = path.as_os_str().to_owned();
+    gz_name.push(".gz");
+    path.exists() || Path::new(&gz_name).exists()
+}
+
+fn compress(path: &Path) -> io::Result<PathBuf> {
+    let mut gz_name = path.as_os_str().to_owned();
+    gz_name.push(".gz");
+    let gz_path = PathBuf::from(gz_name);
+
+    let mut input = BufReader::new(File::open(path)?);
+    let mut encoder = GzEncoder::new(
+        BufWriter::new(File::create(&gz_path)?),
+        Compression::default(),
+    );
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    std::fs::remove_file(path)?;
+    Ok(gz_path)
+}
+
+impl ArchiveSink {
+    pub fn new(config: &ArchiveConfig) -> Result<(Self, ArchiveWriter)> {
+        if config.max_size_mb == Some(0) {
+            bail!("max_size_mb must be at least 1");
+        }
+        if let Some(dir) = config.path.parent()
+            && !dir.as_os_str().is_empty()
+            && !dir.is_dir()
+        {
+            bail!("Archive directory {} doesn't exist", dir.display());
+        }
+
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+
+        Ok((
+            Self {
+                tx,
+                path: config.path.clone(),
+            },
+            ArchiveWriter {
+                rx,
+                file: ArchiveFile {
+                    path: config.path.clone(),
+                    max_size: config.max_size_mb.map(|mb| mb * BYTES_PER_MB),
+                    daily: config.daily,
+                    gzip: config.gzip,
+                    file: None,
+                    size: 0,
+                    date: Utc::now().date_naive(),
+                },
+            },
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, event: &Event) {
+        if self.tx.try_send(event.clone()).is_err() {
+            warn!("{}", fl!("archive-queue-full", event = event.kind()));
+        }
+    }
+}
+
+impl ArchiveFile {
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            let metadata = file.metadata()?;
+            self.size = metadata.len();
+            // A file left over from an earlier run belongs to the day it was last written
+            self.date = match metadata.modified() {
+                Ok(modified) if self.size > 0 => DateTime::<Utc>::from(modified).date_naive(),
+                _ => Utc::now().date_naive(),
+            };
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().expect("opened above"))
+    }
+
+    fn needs_rotation(&self, line_len: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_big = self.max_size.is_some_and(|max| self.size + line_len > max);
+        let new_day = self.daily && Utc::now().date_naive() != self.date;
+        too_big || new_day
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let now = Utc::now();
+        let mut counter = 0;
+        let mut rotated = rotated_path(&self.path, now, counter);
+        while is_taken(&rotated) {
+            counter += 1;
+            rotated = rotated_path(&self.path, now, counter);
+        }
+        std::fs::rename(&self.path, &rotated)?;
+        info!(
+            "{}",
+            fl!("archive-rotated", path = rotated.display().to_string())
+        );
+
+        if self.gzip {
+            // Compressing a big file takes a while, new events go to the fresh file meanwhile
+            tokio::task::spawn_blocking(move || match compress(&rotated) {
+                Ok(gz_path) => debug!(
+                    "{}",
+                    fl!("archive-compressed", path = gz_path.display().to_string())
+                ),
+                Err(e) => warn!(
+                    "{}",
+                    fl!(
+                        "failed-to-compress-archive",
+                        path = rotated.display().to_string(),
+                        error = e.to_string()
+                    )
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, event: &Event) -> Result<()> {
+        let mut line = serde_json::to_string(&CloudEvent::new(event)?)?;
+        line.push('\n');
+
+        self.open()?;
+        if self.needs_rotation(line.len() as u64) {
+            self.rotate().context("Failed to rotate the archive")?;
+        }
+
+        let file = self.open()?;
+        file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn write_all(&mut self, events: &[Event]) {
+        for event in events {
+            if let Err(e) = self.write(event) {
+                warn!(
+                    "{}",
+                    fl!("failed-to-write-archive", error = format!("{:#}", e))
+                );
+            }
+        }
+    }
+}
+
+// Writes a batch on a blocking thread, the file comes back for the next batch
+async fn write_batch(mut file: ArchiveFile, events: Vec<Event>) -> Result<ArchiveFile> {
+    tokio::task::spawn_blocking(move || {
+        file.write_all(&events);
+        file
+    })
+    .await
+    .context("Archive writer thread failed")
+}
+
+impl ArchiveWriter {
+    pub async fn run(self, subsys: SubsystemHandle) -> Result<()> {
+        let ArchiveWriter { mut rx, mut file } = self;
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => {
+                        // Events that piled up while the last batch was written go in one batch
+                        let mut events = vec![event];
+                        while let Ok(event) = rx.try_recv() {
+                            events.push(event);
+                        }
+                        file = write_batch(file, events).await?;
+                    }
+                    None => break,
+                },
+                _ = subsys.on_shutdown_requested() => break,
+            }
+        }
+
+        // Events that were already queued still belong to the archive
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        if !events.is_empty() {
+            write_batch(file, events).await?;
+        }
+        debug!("{}", fl!("archive-shutdown"));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_names_keep_the_extension() {
+        let now = DateTime::from_timestamp(1714564800, 0).unwrap();
+        let path = Path::new("/var/lib/emtt/archive.jsonl");
+        assert_eq!(
+            rotated_path(path, now, 0),
+            Path::new("/var/lib/emtt/archive-20240501-120000.jsonl")
+        );
+        assert_eq!(
+            rotated_path(path, now, 2),
+            Path::new("/var/lib/emtt/archive-20240501-120000-2.jsonl")
+        );
+        assert_eq!(
+            rotated_path(Path::new("archive"), now, 1),
+            Path::new("archive-20240501-120000-1")
+        );
+    }
+}
@@ -0,0 +1,359 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::Result;
+use chrono::{Local, TimeZone};
+use log::{debug, warn};
+use std::time::Duration;
+use teloxide::dispatching::{Dispatcher, UpdateFilterExt};
+use teloxide::dptree;
+use teloxide::prelude::*;
+use teloxide::types::{
+    BotCommand, BotCommandScope, CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup,
+    Recipient,
+};
+use teloxide::utils::command::BotCommands;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+use crate::fl;
+use crate::gateways::{GatewayMonitor, GatewayStatus};
+use crate::monitoring::Health;
+use crate::nodes::{Node, NodeStore, Reception};
+
+const NODE_INFO_PREFIX: &str = "node:";
+const SIGNAL_HISTORY_PREFIX: &str = "signal:";
+
+// Telegram limits callback answers shown as alerts to 200 characters
+const ANSWER_MAX_CHARS: usize = 200;
+
+const GET_ME_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+// How many receptions fit into a signal history answer
+const HISTORY_ENTRIES: usize = 4;
+
+// Descriptions are localized and registered in `register_commands`
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase")]
+enum Command {
+    Gateways,
+}
+
+// Chats the bot answers commands in
+#[derive(Clone)]
+struct CommandChats(Vec<i64>);
+
+pub fn node_keyboard(node_id: u32) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback(
+            fl!("button-node-info"),
+            format!("{}{:08x}", NODE_INFO_PREFIX, node_id),
+        ),
+        InlineKeyboardButton::callback(
+            fl!("button-signal-history"),
+            format!("{}{:08x}", SIGNAL_HISTORY_PREFIX, node_id),
+        ),
+    ]])
+}
+
+fn format_time(timestamp: u64) -> String {
+    Local
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map(|t| t.format("%d.%m %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+fn format_opt<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "?".to_string())
+}
+
+fn node_name(node: &Node, node_id: u32) -> String {
+    node.info
+        .as_ref()
+        .map(|i| i.longname.clone())
+        .unwrap_or_else(|| format!("!{:08x}", node_id))
+}
+
+fn format_reception(reception: &Reception) -> String {
+    fl!(
+        "signal-history-entry",
+        time = format_time(reception.timestamp),
+        via = reception.via.clone(),
+        snr = format_opt(reception.snr),
+        rssi = format_opt(reception.rssi),
+        hops = format_opt(reception.hops_away)
+    )
+}
+
+fn node_info_text(node: &Node, node_id: u32) -> String {
+    let mut lines = Vec::new();
+
+    match &node.info {
+        Some(info) => lines.push(fl!(
+            "node-info-name",
+            longname = info.longname.clone(),
+            shortname = info.shortname.clone()
+        )),
+        None => lines.push(fl!("node-info-no-name")),
+    }
+    lines.push(format!("ID: !{:08x}", node_id));
+
+    if let Some(last) = node.last_seen() {
+        lines.push(fl!(
+            "node-info-last-seen",
+            time = format_time(last.timestamp),
+            via = last.via.clone()
+        ));
+        lines.push(format_reception(last));
+    }
+
+    lines.join("\n")
+}
+
+fn signal_history_text(node: &Node, node_id: u32) -> String {
+    if node.history.is_empty() {
+        return fl!("signal-history-empty", name = node_name(node, node_id));
+    }
+
+    let mut lines = vec![fl!("signal-history-title", name = node_name(node, node_id))];
+    lines.extend(
+        node.history
+            .iter()
+            .rev()
+            .take(HISTORY_ENTRIES)
+            .map(format_reception),
+    );
+    lines.join("\n")
+}
+
+fn gateway_text(status: &GatewayStatus) -> String {
+    let last_seen = status
+        .last_seen
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| format_time(d.as_secs()))
+        .unwrap_or_default();
+    let mut text = if status.silent {
+        fl!(
+            "gateway-status-silent",
+            gateway = status.ident.clone(),
+            time = last_seen
+        )
+    } else {
+        fl!(
+            "gateway-status",
+            gateway = status.ident.clone(),
+            time = last_seen
+        )
+    };
+
+    let Some(device) = &status.device else {
+        return text;
+    };
+    let mut parts = Vec::new();
+    if device.on_external_power() {
+        parts.push(fl!("gateway-external-power"));
+    } else if let Some(battery) = device.battery_level {
+        parts.push(fl!("gateway-battery", level = format!("{:.0}", battery)));
+    }
+    if let Some(voltage) = device.voltage {
+        parts.push(format!("{:.2} V", voltage));
+    }
+    if let Some(utilization) = device.channel_utilization {
+        parts.push(fl!(
+            "gateway-channel-utilization",
+            value = format!("{:.1}", utilization)
+        ));
+    }
+    if let Some(air_util_tx) = device.air_util_tx {
+        parts.push(fl!(
+            "gateway-air-util-tx",
+            value = format!("{:.1}", air_util_tx)
+        ));
+    }
+    if let Some(uptime) = device.uptime {
+        parts.push(fl!("gateway-uptime", hours = (uptime / 3600).to_string()));
+    }
+    if !parts.is_empty() {
+        text.push('\n');
+        text.push_str(&parts.join(", "));
+    }
+    text
+}
+
+fn gateways_text(statuses: &[GatewayStatus]) -> String {
+    if statuses.is_empty() {
+        return fl!("gateways-none");
+    }
+    statuses
+        .iter()
+        .map(gateway_text)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn truncate(text: String) -> String {
+    if text.chars().count() <= ANSWER_MAX_CHARS {
+        return text;
+    }
+    let mut truncated: String = text.chars().take(ANSWER_MAX_CHARS - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+async fn answer_text(data: &str, nodes: &NodeStore) -> Option<String> {
+    let (is_history, hex) = if let Some(hex) = data.strip_prefix(NODE_INFO_PREFIX) {
+        (false, hex)
+    } else if let Some(hex) = data.strip_prefix(SIGNAL_HISTORY_PREFIX) {
+        (true, hex)
+    } else {
+        return None;
+    };
+
+    let node_id = u32::from_str_radix(hex, 16).ok()?;
+    let text = match nodes.get(node_id).await {
+        Some(node) if is_history => signal_history_text(&node, node_id),
+        Some(node) => node_info_text(&node, node_id),
+        None => fl!("node-unknown", id = format!("!{:08x}", node_id)),
+    };
+
+    Some(truncate(text))
+}
+
+async fn handle_callback(bot: Bot, query: CallbackQuery, nodes: NodeStore) -> ResponseResult<()> {
+    let text = match query.data.as_deref() {
+        Some(data) => answer_text(data, &nodes).await,
+        None => None,
+    };
+
+    debug!(
+        "{}",
+        fl!(
+            "callback-received",
+            user = query.from.id.to_string(),
+            data = query.data.clone().unwrap_or_default()
+        )
+    );
+
+    let mut answer = bot.answer_callback_query(query.id);
+    if let Some(text) = text {
+        answer = answer.text(text).show_alert(true);
+    }
+    answer.await?;
+    Ok(())
+}
+
+// Returns false if shutdown was requested before Telegram answered
+async fn wait_for_telegram(subsys: &SubsystemHandle, bot: &Bot) -> bool {
+    loop {
+        tokio::select! {
+            res = bot.get_me() => match res {
+                Ok(_) => return true,
+                Err(e) => {
+                    warn!("{}", fl!("bot-get-me-failed", error = e.to_string()));
+                }
+            },
+            _ = subsys.on_shutdown_requested() => return false,
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(GET_ME_RETRY_INTERVAL) => {}
+            _ = subsys.on_shutdown_requested() => return false,
+        }
+    }
+}
+
+// Only used for readiness when the bot doesn't receive updates
+pub async fn check_telegram(subsys: SubsystemHandle, bot: Bot, health: Health) -> Result<()> {
+    if wait_for_telegram(&subsys, &bot).await {
+        health.set_telegram_ready();
+    }
+    Ok(())
+}
+
+async fn handle_command(
+    bot: Bot,
+    msg: Message,
+    command: Command,
+    gateways: GatewayMonitor,
+    chats: CommandChats,
+) -> ResponseResult<()> {
+    // Gateway details are only for the chats messages are forwarded to
+    if !chats.0.contains(&msg.chat.id.0) {
+        debug!(
+            "{}",
+            fl!("command-ignored", chat_id = msg.chat.id.0.to_string())
+        );
+        return Ok(());
+    }
+
+    let text = match command {
+        Command::Gateways => gateways_text(&gateways.statuses()),
+    };
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
+// Shows the command menu in the chats that may use it
+async fn register_commands(bot: &Bot, chat_ids: &[i64]) {
+    let commands = [BotCommand::new("gateways", fl!("bot-command-gateways"))];
+    for chat_id in chat_ids {
+        let scope = BotCommandScope::Chat {
+            chat_id: Recipient::Id(ChatId(*chat_id)),
+        };
+        if let Err(err) = bot.set_my_commands(commands.clone()).scope(scope).await {
+            warn!(
+                "{}",
+                fl!(
+                    "failed-to-register-commands",
+                    chat_id = chat_id.to_string(),
+                    error = err.to_string()
+                )
+            );
+        }
+    }
+}
+
+pub async fn run_bot(
+    subsys: SubsystemHandle,
+    bot: Bot,
+    nodes: NodeStore,
+    gateways: GatewayMonitor,
+    health: Health,
+    command_chats: Option<Vec<i64>>,
+) -> Result<()> {
+    // The dispatcher panics if it can't reach Telegram on startup, so wait for it first
+    if !wait_for_telegram(&subsys, &bot).await {
+        return Ok(());
+    }
+    health.set_telegram_ready();
+
+    let mut handler =
+        dptree::entry().branch(Update::filter_callback_query().endpoint(handle_callback));
+    if let Some(chat_ids) = &command_chats {
+        register_commands(&bot, chat_ids).await;
+        handler = handler.branch(
+            Update::filter_message()
+                .filter_command::<Command>()
+                .endpoint(handle_command),
+        );
+    }
+    let chats = CommandChats(command_chats.unwrap_or_default());
+
+    let mut dispatcher = Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![nodes, gateways, chats])
+        .default_handler(|_| async {})
+        .build();
+
+    // Dropping the dispatcher future stops long polling right away
+    tokio::select! {
+        _ = dispatcher.dispatch() => {}
+        _ = subsys.on_shutdown_requested() => {
+            debug!("{}", fl!("bot-shutdown"));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::alerts::AlertConfig;
+use crate::archive::ArchiveConfig;
+use crate::discord::DiscordConfig;
+use crate::email::EmailConfig;
+use crate::exec::ExecConfig;
+use crate::gateways::GatewaysConfig;
+use crate::matrix::MatrixConfig;
+use crate::mqtt::MqttConfig;
+use crate::notifications::NotificationsConfig;
+use crate::push::{GotifyConfig, NtfyConfig};
+use crate::roster::RosterConfig;
+use crate::webhook::WebhookConfig;
+
+// Optional TOML file for settings that don't fit into flags and env vars
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub notifications: NotificationsConfig,
+    pub alerts: Vec<AlertConfig>,
+    pub webhooks: Vec<WebhookConfig>,
+    pub discord: Vec<DiscordConfig>,
+    pub matrix: Option<MatrixConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub ntfy: Vec<NtfyConfig>,
+    pub gotify: Vec<GotifyConfig>,
+    pub email: Option<EmailConfig>,
+    pub exec: Vec<ExecConfig>,
+    pub archive: Option<ArchiveConfig>,
+    pub gateways: Option<GatewaysConfig>,
+    pub nodes: Option<RosterConfig>,
+}
+
+pub fn load(path: &Path) -> Result<ConfigFile> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))
+}
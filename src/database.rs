@@ -0,0 +1,593 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use clap::ValueEnum;
+use log::{debug, warn};
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags, params};
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+use crate::events::Event;
+use crate::fl;
+
+// Bumped together with a migration in `migrate`
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    packet_id INTEGER NOT NULL,
+    received_at INTEGER NOT NULL,
+    from_id INTEGER NOT NULL,
+    sender TEXT NOT NULL,
+    shortname TEXT,
+    longname TEXT,
+    to_id INTEGER,
+    channel INTEGER,
+    via TEXT NOT NULL,
+    text TEXT NOT NULL,
+    snr REAL,
+    rssi INTEGER,
+    hops_away INTEGER,
+    rx_time INTEGER,
+    reply_id INTEGER,
+    is_reaction INTEGER NOT NULL DEFAULT 0,
+    ignore_reason TEXT
+);
+CREATE INDEX messages_received_at ON messages (received_at);
+CREATE INDEX messages_from_id ON messages (from_id);
+";
+
+// Messages waiting to be stored, extra ones are dropped
+const QUEUE_CAPACITY: usize = 1000;
+
+#[derive(Clone)]
+pub struct MessageDb {
+    tx: mpsc::Sender<Event>,
+    path: PathBuf,
+}
+
+// Owns the database connection, runs as a subsystem
+pub struct MessageDbWriter {
+    rx: mpsc::Receiver<Event>,
+    conn: Connection,
+}
+
+// Filters shared by `archive search` and `archive export`
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub from: Option<String>,
+    pub text: Option<String>,
+    pub via: Option<String>,
+    pub channel: Option<u32>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ExportFormat {
+    #[value(name = "csv", help = fl!("export-format-csv"))]
+    Csv,
+    #[value(name = "json", help = fl!("export-format-json"))]
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct Row {
+    received_at: String,
+    from_id: String,
+    sender: String,
+    shortname: Option<String>,
+    longname: Option<String>,
+    to_id: Option<String>,
+    channel: Option<u32>,
+    via: String,
+    text: String,
+    snr: Option<f64>,
+    rssi: Option<i32>,
+    hops_away: Option<i32>,
+    packet_id: u32,
+    ignore_reason: Option<String>,
+}
+
+const COLUMNS: &str = "received_at, from_id, sender, shortname, longname, to_id, channel, via, \
+                       text, snr, rssi, hops_away, packet_id, ignore_reason";
+
+fn node_id(id: i64) -> String {
+    format!("!{:08x}", id)
+}
+
+fn open(path: &Path, flags: OpenFlags) -> Result<Connection> {
+    let conn = Connection::open_with_flags(path, flags)
+        .with_context(|| format!("Failed to open database {}", path.display()))?;
+    // Searching from the command line while the writer is busy
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    Ok(conn)
+}
+
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    match version {
+        0 => {
+            conn.execute_batch(SCHEMA)?;
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        SCHEMA_VERSION => {}
+        v => bail!(
+            "Database schema version {} is newer than this EMtT supports",
+            v
+        ),
+    }
+    Ok(())
+}
+
+fn insert(conn: &Connection, event: &Event) -> rusqlite::Result<()> {
+    const SQL: &str = "INSERT INTO messages (packet_id, received_at, from_id, sender, shortname, \
+                       longname, to_id, channel, via, text, snr, rssi, hops_away, rx_time, \
+                       reply_id, is_reaction, ignore_reason) \
+                       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)";
+    match event {
+        Event::Text(data) => conn.execute(
+            SQL,
+            params![
+                data.id,
+                data.received_at as i64,
+                data.from_id,
+                data.from,
+                data.shortname,
+                data.longname,
+                data.to,
+                data.channel,
+                data.via,
+                data.text,
+                data.snr,
+                data.rssi,
+                data.hops_away,
+                data.rx_time.map(|t| t as i64),
+                data.reply_id,
+                data.is_reaction,
+                Option::<String>::None,
+            ],
+        ),
+        Event::Ignored(ignored) => conn.execute(
+            SQL,
+            params![
+                ignored.id,
+                ignored.received_at as i64,
+                ignored.from_id,
+                format!("0x{:08x}", ignored.from_id),
+                Option::<String>::None,
+                Option::<String>::None,
+                ignored.to,
+                ignored.channel,
+                ignored.via,
+                ignored.text,
+                Option::<f32>::None,
+                Option::<i32>::None,
+                Option::<i32>::None,
+                Option::<i64>::None,
+                Option::<u32>::None,
+                false,
+                ignored.reason.name(),
+            ],
+        ),
+        Event::NodeInfo(_) | Event::Telemetry(_) => return Ok(()),
+    }
+    .map(|_| ())
+}
+
+// Accepts RFC 3339, "YYYY-MM-DD", "YYYY-MM-DD HH:MM[:SS]" in local time, or an age like "30m", "2h", "7d"
+fn parse_time(value: &str) -> Result<i64> {
+    let value = value.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Ok(t.timestamp());
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(value, format)
+            && let Some(t) = Local.from_local_datetime(&t).earliest()
+        {
+            return Ok(t.timestamp());
+        }
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        && let Some(t) = Local
+            .from_local_datetime(&d.and_hms_opt(0, 0, 0).unwrap())
+            .earliest()
+    {
+        return Ok(t.timestamp());
+    }
+
+    let unit = value.chars().last().unwrap_or_default();
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => bail!("Invalid time {:?}", value),
+    };
+    let number: i64 = value[..value.len() - unit.len_utf8()]
+        .parse()
+        .with_context(|| format!("Invalid time {:?}", value))?;
+    number
+        .checked_mul(seconds)
+        .and_then(|ago| Utc::now().timestamp().checked_sub(ago))
+        .with_context(|| format!("Time {:?} is out of range", value))
+}
+
+// "!aabbccdd", "0xaabbccdd" or a bare 8-digit hex ID
+fn parse_node_id(value: &str) -> Option<u32> {
+    let hex = value
+        .strip_prefix('!')
+        .or_else(|| value.strip_prefix("0x"))
+        .unwrap_or(value);
+    if hex.len() == 8 {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        None
+    }
+}
+
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('%');
+    for ch in value.chars() {
+        if matches!(ch, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped.push('%');
+    escaped
+}
+
+impl Filter {
+    fn to_sql(&self) -> Result<(String, Vec<Value>)> {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+
+        if let Some(from) = &self.from {
+            match parse_node_id(from) {
+                Some(id) => {
+                    conditions.push("from_id = ?".to_string());
+                    params.push(Value::Integer(id.into()));
+                }
+                None => {
+                    conditions.push(
+                        "(sender LIKE ? ESCAPE '\\' OR shortname LIKE ? ESCAPE '\\')".to_string(),
+                    );
+                    params.push(Value::Text(escape_like(from)));
+                    params.push(Value::Text(escape_like(from)));
+                }
+            }
+        }
+        if let Some(text) = &self.text {
+            conditions.push("text LIKE ? ESCAPE '\\'".to_string());
+            params.push(Value::Text(escape_like(text)));
+        }
+        if let Some(via) = &self.via {
+            conditions.push("via = ?".to_string());
+            params.push(Value::Text(via.clone()));
+        }
+        if let Some(channel) = self.channel {
+            conditions.push("channel = ?".to_string());
+            params.push(Value::Integer(channel.into()));
+        }
+        if let Some(since) = &self.since {
+            conditions.push("received_at >= ?".to_string());
+            params.push(Value::Integer(parse_time(since)?));
+        }
+        if let Some(until) = &self.until {
+            conditions.push("received_at < ?".to_string());
+            params.push(Value::Integer(parse_time(until)?));
+        }
+
+        let clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        Ok((clause, params))
+    }
+}
+
+// Rows in chronological order; with a limit, the latest ones
+fn query(path: &Path, filter: &Filter, limit: Option<u32>) -> Result<Vec<Row>> {
+    let conn = open(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let (clause, mut params) = filter.to_sql()?;
+    let sql = match limit {
+        Some(limit) => {
+            params.push(Value::Integer(limit.into()));
+            format!(
+                "SELECT * FROM (SELECT {}, id FROM messages {} ORDER BY received_at DESC, id DESC LIMIT ?) \
+                 ORDER BY received_at, id",
+                COLUMNS, clause
+            )
+        }
+        None => format!(
+            "SELECT {} FROM messages {} ORDER BY received_at, id",
+            COLUMNS, clause
+        ),
+    };
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok(Row {
+            received_at: DateTime::from_timestamp(row.get(0)?, 0)
+                .unwrap_or_default()
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            from_id: node_id(row.get(1)?),
+            sender: row.get(2)?,
+            shortname: row.get(3)?,
+            longname: row.get(4)?,
+            to_id: row.get::<_, Option<i64>>(5)?.map(node_id),
+            channel: row.get(6)?,
+            via: row.get(7)?,
+            text: row.get(8)?,
+            snr: row.get(9)?,
+            rssi: row.get(10)?,
+            hops_away: row.get(11)?,
+            packet_id: row.get(12)?,
+            ignore_reason: row.get(13)?,
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+pub fn search(path: &Path, filter: &Filter, limit: u32) -> Result<()> {
+    let rows = query(path, filter, Some(limit))?;
+    let mut out = std::io::stdout().lock();
+    for row in &rows {
+        let channel = match (row.channel, row.to_id.as_deref()) {
+            (_, Some(to)) if to != "!ffffffff" => format!("DM {}", to),
+            (Some(ch), _) => format!("ch{}", ch),
+            (None, _) => "?".to_string(),
+        };
+        write!(
+            out,
+            "{}  {} {} (via {}, {})",
+            row.received_at, row.from_id, row.sender, row.via, channel
+        )?;
+        if let Some(reason) = &row.ignore_reason {
+            write!(
+                out,
+                " [{}]",
+                fl!("archive-ignored", reason = reason.clone())
+            )?;
+        }
+        writeln!(out, ": {}", row.text)?;
+    }
+    if rows.is_empty() {
+        eprintln!("{}", fl!("archive-no-results"));
+    }
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_csv(out: &mut impl Write, rows: &[Row]) -> Result<()> {
+    writeln!(out, "{}", COLUMNS.replace(' ', ""))?;
+    for row in rows {
+        let fields = [
+            row.received_at.clone(),
+            row.from_id.clone(),
+            row.sender.clone(),
+            row.shortname.clone().unwrap_or_default(),
+            row.longname.clone().unwrap_or_default(),
+            row.to_id.clone().unwrap_or_default(),
+            row.channel.map(|c| c.to_string()).unwrap_or_default(),
+            row.via.clone(),
+            row.text.clone(),
+            row.snr.map(|v| v.to_string()).unwrap_or_default(),
+            row.rssi.map(|v| v.to_string()).unwrap_or_default(),
+            row.hops_away.map(|v| v.to_string()).unwrap_or_default(),
+            row.packet_id.to_string(),
+            row.ignore_reason.clone().unwrap_or_default(),
+        ];
+        let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        writeln!(out, "{}", line.join(","))?;
+    }
+    Ok(())
+}
+
+pub fn export(
+    path: &Path,
+    filter: &Filter,
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> Result<()> {
+    let rows = query(path, filter, None)?;
+    let mut out: Box<dyn Write> = match output {
+        Some(p) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(p)
+                .with_context(|| format!("Failed to create {}", p.display()))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    match format {
+        ExportFormat::Csv => write_csv(&mut out, &rows)?,
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &rows)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    if output.is_some() {
+        eprintln!("{}", fl!("archive-exported", count = rows.len()));
+    }
+    Ok(())
+}
+
+impl MessageDb {
+    pub fn new(path: &Path) -> Result<(Self, MessageDbWriter)> {
+        let conn = open(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        )?;
+        // WAL lets `emtt archive search` read while messages are being written
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        migrate(&conn).with_context(|| format!("Failed to set up database {}", path.display()))?;
+
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        Ok((
+            Self {
+                tx,
+                path: path.to_path_buf(),
+            },
+            MessageDbWriter { rx, conn },
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, event: &Event) {
+        if !matches!(event, Event::Text(_) | Event::Ignored(_)) {
+            return;
+        }
+        if self.tx.try_send(event.clone()).is_err() {
+            warn!("{}", fl!("database-queue-full", event = event.kind()));
+        }
+    }
+}
+
+fn store(conn: &Connection, events: &[Event]) {
+    for event in events {
+        if let Err(e) = insert(conn, event) {
+            warn!("{}", fl!("failed-to-write-database", error = e.to_string()));
+        }
+    }
+}
+
+// Inserts a batch on a blocking thread, the connection comes back for the next batch
+async fn store_batch(conn: Connection, events: Vec<Event>) -> Result<Connection> {
+    tokio::task::spawn_blocking(move || {
+        store(&conn, &events);
+        conn
+    })
+    .await
+    .context("Database writer thread failed")
+}
+
+impl MessageDbWriter {
+    pub async fn run(self, subsys: SubsystemHandle) -> Result<()> {
+        let MessageDbWriter { mut rx, mut conn } = self;
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => {
+                        // Messages that arrived while the last batch was stored go in one batch
+                        let mut events = vec![event];
+                        while let Ok(event) = rx.try_recv() {
+                            events.push(event);
+                        }
+                        conn = store_batch(conn, events).await?;
+                    }
+                    None => break,
+                },
+                _ = subsys.on_shutdown_requested() => break,
+            }
+        }
+
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        if !events.is_empty() {
+            store_batch(conn, events).await?;
+        }
+        debug!("{}", fl!("database-shutdown"));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_absolute_times() {
+        assert_eq!(parse_time("2024-05-01T12:00:00Z").unwrap(), 1714564800);
+        assert_eq!(parse_time("2024-05-01T15:00:00+03:00").unwrap(), 1714564800);
+
+        let local = |s: &str| {
+            let t = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+            Local
+                .from_local_datetime(&t)
+                .earliest()
+                .unwrap()
+                .timestamp()
+        };
+        assert_eq!(
+            parse_time("2024-05-01 12:30:15").unwrap(),
+            local("2024-05-01 12:30:15")
+        );
+        assert_eq!(
+            parse_time("2024-05-01 12:30").unwrap(),
+            local("2024-05-01 12:30:00")
+        );
+        assert_eq!(
+            parse_time(" 2024-05-01 ").unwrap(),
+            local("2024-05-01 00:00:00")
+        );
+    }
+
+    #[test]
+    fn parses_relative_times() {
+        let now = Utc::now().timestamp();
+        for (value, seconds) in [
+            ("30s", 30),
+            ("15m", 900),
+            ("2h", 7200),
+            ("1d", 86400),
+            ("1w", 604800),
+        ] {
+            let ago = now - parse_time(value).unwrap();
+            assert!((seconds..seconds + 5).contains(&ago), "{value}: {ago}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        for value in ["", "yesterday", "5y", "h", "1.5h", "2024-13-01"] {
+            assert!(parse_time(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_ages() {
+        for value in [
+            "999999999999999w",
+            "-9223372036854775807s",
+            "-9223372036854775807d",
+        ] {
+            assert!(parse_time(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn quotes_csv_fields_when_needed() {
+        assert_eq!(csv_field("plain text"), "plain text");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+        assert_eq!(csv_field(""), "");
+    }
+}
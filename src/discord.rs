@@ -0,0 +1,255 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use minijinja::context;
+use minijinja::{AutoEscape, Environment};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::http::{RetryPolicy, send_with_retry};
+use crate::notifications::{MessageKind, route_matches};
+use crate::{MessageData, fl};
+
+const DEFAULT_TEMPLATE: &str = "**{{ from }}** (via *{{ via }}*)\n>>> {{ text }}";
+
+// Discord rejects messages longer than this
+const CONTENT_MAX_CHARS: usize = 2000;
+
+// A `[[discord]]` route of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordConfig {
+    webhook_url: String,
+    template: Option<String>,
+    username: Option<String>,
+    avatar_url: Option<String>,
+    #[serde(default)]
+    messages: MessageKind,
+    #[serde(default)]
+    channels: Vec<u32>,
+}
+
+#[derive(Clone)]
+struct DiscordRoute {
+    webhook_url: String,
+    template: String,
+    username: Option<String>,
+    avatar_url: Option<String>,
+    messages: MessageKind,
+    channels: Vec<u32>,
+}
+
+#[derive(Clone)]
+pub struct DiscordSink {
+    routes: Vec<DiscordRoute>,
+    policy: RetryPolicy,
+}
+
+// Backslash before any markdown character makes Discord show it as is
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for ch in s.chars() {
+        if matches!(
+            ch,
+            '\\' | '*' | '_' | '~' | '`' | '|' | '>' | '#' | '-' | '[' | ']' | '(' | ')'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+fn create_template_env() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_auto_escape_callback(|_name| AutoEscape::Custom("discord_markdown"));
+    env.set_formatter(crate::telegram_escape_formatter);
+    env
+}
+
+fn truncate(text: String) -> String {
+    if text.chars().count() <= CONTENT_MAX_CHARS {
+        return text;
+    }
+    let mut truncated: String = text.chars().take(CONTENT_MAX_CHARS - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+impl DiscordRoute {
+    fn compile(config: &DiscordConfig) -> Result<Self> {
+        let template = config
+            .template
+            .clone()
+            .map(crate::unescape_template)
+            .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+
+        create_template_env()
+            .template_from_str(&template)
+            .with_context(|| format!("Invalid Discord template for {}", config.webhook_url))?;
+
+        Ok(Self {
+            webhook_url: config.webhook_url.clone(),
+            template,
+            username: config.username.clone(),
+            avatar_url: config.avatar_url.clone(),
+            messages: config.messages,
+            channels: config.channels.clone(),
+        })
+    }
+
+    fn matches(&self, data: &MessageData) -> bool {
+        route_matches(self.messages, &self.channels, data)
+    }
+
+    fn render(&self, data: &MessageData) -> Result<String, minijinja::Error> {
+        let ctx = context! {
+            from => data.from.clone(),
+            is_name_conflict => data.is_name_conflict,
+            via => data.via.clone(),
+            text => data.text.clone(),
+            snr => data.snr,
+            rssi => data.rssi,
+            hops_away => data.hops_away,
+            channel => data.channel,
+        };
+        create_template_env().render_str(&self.template, ctx)
+    }
+
+    fn payload(&self, content: String) -> serde_json::Value {
+        // Mesh users shouldn't be able to ping @everyone
+        let mut payload = json!({
+            "content": content,
+            "allowed_mentions": { "parse": [] },
+        });
+        if let Some(username) = &self.username {
+            payload["username"] = json!(username);
+        }
+        if let Some(avatar_url) = &self.avatar_url {
+            payload["avatar_url"] = json!(avatar_url);
+        }
+        payload
+    }
+}
+
+impl DiscordSink {
+    pub fn compile(configs: &[DiscordConfig], policy: RetryPolicy) -> Result<Self> {
+        let routes = configs
+            .iter()
+            .map(DiscordRoute::compile)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { routes, policy })
+    }
+
+    pub fn route_count(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub async fn deliver(&self, client: &Client, data: &MessageData) {
+        for route in self.routes.iter().filter(|r| r.matches(data)) {
+            let content = match route.render(data) {
+                Ok(c) => truncate(c),
+                Err(e) => {
+                    warn!("{}", fl!("failed-to-render", error = e.to_string()));
+                    continue;
+                }
+            };
+
+            let payload = route.payload(content.clone());
+
+            match send_with_retry(&self.policy, || {
+                client.post(&route.webhook_url).json(&payload)
+            })
+            .await
+            {
+                Ok(_) => {
+                    metrics::counter!("emtt_sink_sends_total", "sink" => "discord", "result" => "success")
+                        .increment(1);
+                    debug!(
+                        "{}",
+                        fl!(
+                            "forwarded-to-discord",
+                            from = data.from.clone(),
+                            message = content.clone()
+                        )
+                    );
+                }
+                Err(err) => {
+                    metrics::counter!("emtt_sink_sends_total", "sink" => "discord", "result" => "failure")
+                        .increment(1);
+                    warn!(
+                        "{}\n{}",
+                        fl!("failed-to-send-discord", error = err.to_string()),
+                        fl!("message-content", content = content.clone())
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(template: Option<&str>) -> DiscordRoute {
+        DiscordRoute::compile(&DiscordConfig {
+            webhook_url: "https://discord.com/api/webhooks/1/x".to_string(),
+            template: template.map(str::to_string),
+            username: None,
+            avatar_url: None,
+            messages: MessageKind::default(),
+            channels: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn escapes_markdown_characters() {
+        assert_eq!(escape("*bold*"), "\\*bold\\*");
+        assert_eq!(escape("_it_"), "\\_it\\_");
+        assert_eq!(escape("`code`"), "\\`code\\`");
+        assert_eq!(escape("~~gone~~"), "\\~\\~gone\\~\\~");
+        assert_eq!(escape("||spoiler||"), "\\|\\|spoiler\\|\\|");
+        assert_eq!(escape("> quote"), "\\> quote");
+        assert_eq!(escape("# title"), "\\# title");
+        assert_eq!(escape("- item"), "\\- item");
+        assert_eq!(escape("[link](https://x)"), "\\[link\\]\\(https://x\\)");
+        assert_eq!(escape("back\\slash"), "back\\\\slash");
+    }
+
+    #[test]
+    fn leaves_plain_text_alone() {
+        assert_eq!(escape("Привет, mesh! 73"), "Привет, mesh! 73");
+    }
+
+    #[test]
+    fn mesh_text_is_escaped_in_templates() {
+        let data = MessageData {
+            from: "*Alice*".to_string(),
+            via: "gw_1".to_string(),
+            text: "@everyone `rm -rf` ||x||".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            route(None).render(&data).unwrap(),
+            "**\\*Alice\\*** (via *gw\\_1*)\n>>> @everyone \\`rm \\-rf\\` \\|\\|x\\|\\|"
+        );
+    }
+
+    #[test]
+    fn mentions_are_disabled() {
+        let route = route(Some("{{ text }}"));
+        let data = MessageData {
+            text: "@everyone @here <@123> <@&456>".to_string(),
+            ..Default::default()
+        };
+        let payload = route.payload(route.render(&data).unwrap());
+        assert_eq!(payload["allowed_mentions"], json!({ "parse": [] }));
+        assert_eq!(payload["content"], "@everyone @here <@123\\> <@&456\\>");
+    }
+}
@@ -0,0 +1,317 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result, bail};
+use chrono::DateTime;
+use lettre::message::{Mailbox, Mailboxes, header::ContentType};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{debug, warn};
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, sleep_until};
+use tokio_graceful_shutdown::SubsystemHandle;
+
+use crate::http::{RetryPolicy, retry};
+use crate::notifications::{MessageKind, route_matches};
+use crate::{MessageData, ParseModeOpt, fl};
+
+const DEFAULT_SUBJECT: &str = "{% if count == 1 %}{{ messages[0].from }} (via {{ messages[0].via }}){% else %}{{ count }} messages from the mesh{% endif %}";
+const DEFAULT_TEMPLATE: &str = "{% for m in messages %}[{{ m.time }}] {{ m.from }} (via {{ m.via }}):\n{{ m.text }}\n\n{% endfor %}";
+
+// Messages arriving within this many seconds of the first one go into the same email
+const DEFAULT_BATCH_WINDOW: u64 = 60;
+
+// A batch is sent right away once it grows this big
+const MAX_BATCH_SIZE: usize = 100;
+
+// Messages waiting for the worker, extra ones are dropped
+const QUEUE_CAPACITY: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    #[default]
+    Starttls,
+    Tls,
+    None,
+}
+
+impl Security {
+    fn default_port(self) -> u16 {
+        match self {
+            Security::Starttls => 587,
+            Security::Tls => 465,
+            Security::None => 25,
+        }
+    }
+}
+
+// The `[email]` section of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    host: String,
+    port: Option<u16>,
+    #[serde(default)]
+    security: Security,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    to: Vec<String>,
+    subject: Option<String>,
+    template: Option<String>,
+    batch_window: Option<u64>,
+    #[serde(default)]
+    messages: MessageKind,
+    #[serde(default)]
+    channels: Vec<u32>,
+}
+
+// A message as the subject and body templates see it
+#[derive(Debug, Serialize)]
+struct BatchedMessage {
+    from: String,
+    via: String,
+    text: String,
+    snr: Option<f32>,
+    rssi: Option<i32>,
+    hops_away: Option<i32>,
+    channel: u32,
+    direct: bool,
+    time: String,
+    is_name_conflict: bool,
+}
+
+impl From<&MessageData> for BatchedMessage {
+    fn from(data: &MessageData) -> Self {
+        Self {
+            from: data.from.clone(),
+            via: data.via.clone(),
+            text: data.text.clone(),
+            snr: data.snr,
+            rssi: data.rssi,
+            hops_away: data.hops_away,
+            channel: data.channel,
+            direct: data.is_direct(),
+            time: DateTime::from_timestamp(data.received_at as i64, 0)
+                .unwrap_or_default()
+                .format("%Y-%m-%d %H:%M:%S UTC")
+                .to_string(),
+            is_name_conflict: data.is_name_conflict,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EmailSink {
+    tx: mpsc::Sender<BatchedMessage>,
+    messages: MessageKind,
+    channels: Vec<u32>,
+    recipients: usize,
+}
+
+// Owns the SMTP connection and sends batches, runs as a subsystem
+pub struct EmailWorker {
+    rx: mpsc::Receiver<BatchedMessage>,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Mailboxes,
+    subject: String,
+    template: String,
+    batch_window: std::time::Duration,
+    policy: RetryPolicy,
+}
+
+impl EmailSink {
+    pub fn new(config: &EmailConfig, policy: RetryPolicy) -> Result<(Self, EmailWorker)> {
+        let from: Mailbox = config
+            .from
+            .parse()
+            .with_context(|| format!("Invalid sender address {}", config.from))?;
+        if config.to.is_empty() {
+            bail!("No email recipients configured");
+        }
+        let mut to = Mailboxes::new();
+        for address in &config.to {
+            to.push(
+                address
+                    .parse()
+                    .with_context(|| format!("Invalid recipient address {}", address))?,
+            );
+        }
+
+        let subject = config
+            .subject
+            .clone()
+            .map(crate::unescape_template)
+            .unwrap_or_else(|| DEFAULT_SUBJECT.to_string());
+        let template = config
+            .template
+            .clone()
+            .map(crate::unescape_template)
+            .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+        let env = crate::create_template_env(ParseModeOpt::None);
+        env.template_from_str(&subject)
+            .context("Invalid email subject template")?;
+        env.template_from_str(&template)
+            .context("Invalid email template")?;
+
+        let mut builder = match config.security {
+            Security::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            }
+            Security::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+            Security::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+        }
+        .port(config.port.unwrap_or(config.security.default_port()))
+        .timeout(Some(policy.timeout));
+        if let Some(username) = &config.username {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                config.password.clone().unwrap_or_default(),
+            ));
+        }
+
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+
+        Ok((
+            Self {
+                tx,
+                messages: config.messages,
+                channels: config.channels.clone(),
+                recipients: config.to.len(),
+            },
+            EmailWorker {
+                rx,
+                transport: builder.build(),
+                from,
+                to,
+                subject,
+                template,
+                batch_window: std::time::Duration::from_secs(
+                    config.batch_window.unwrap_or(DEFAULT_BATCH_WINDOW),
+                ),
+                policy,
+            },
+        ))
+    }
+
+    pub fn recipient_count(&self) -> usize {
+        self.recipients
+    }
+
+    // Only queues the message, the worker decides when the email goes out
+    pub fn deliver(&self, data: &MessageData) {
+        if !route_matches(self.messages, &self.channels, data) {
+            return;
+        }
+        if self.tx.try_send(BatchedMessage::from(data)).is_err() {
+            metrics::counter!("emtt_sink_sends_total", "sink" => "email", "result" => "failure")
+                .increment(1);
+            warn!("{}", fl!("email-queue-full", from = data.from.clone()));
+        }
+    }
+}
+
+impl EmailWorker {
+    fn render(&self, batch: &[BatchedMessage]) -> Result<(String, String), minijinja::Error> {
+        let ctx = context! {
+            messages => batch,
+            count => batch.len(),
+        };
+        let env = crate::create_template_env(ParseModeOpt::None);
+        Ok((
+            env.render_str(&self.subject, &ctx)?,
+            env.render_str(&self.template, &ctx)?,
+        ))
+    }
+
+    async fn send(&self, batch: Vec<BatchedMessage>) {
+        let (subject, body) = match self.render(&batch) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{}", fl!("failed-to-render", error = e.to_string()));
+                return;
+            }
+        };
+
+        let email = match Message::builder()
+            .from(self.from.clone())
+            .mailbox(lettre::message::header::To::from(self.to.clone()))
+            // Subjects can't span lines
+            .subject(subject.lines().next().unwrap_or_default())
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.clone())
+        {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("{}", fl!("failed-to-send-email", error = e.to_string()));
+                return;
+            }
+        };
+
+        // Permanent errors mean the server rejected the message itself
+        let result = retry(
+            &self.policy,
+            || self.transport.send(email.clone()),
+            |err: &lettre::transport::smtp::Error| !err.is_permanent(),
+        )
+        .await;
+        match result {
+            Ok(_) => {
+                metrics::counter!("emtt_sink_sends_total", "sink" => "email", "result" => "success")
+                    .increment(1);
+                debug!(
+                    "{}",
+                    fl!("forwarded-to-email", count = batch.len(), message = body)
+                );
+            }
+            Err(err) => {
+                metrics::counter!("emtt_sink_sends_total", "sink" => "email", "result" => "failure")
+                    .increment(1);
+                warn!(
+                    "{}\n{}",
+                    fl!("failed-to-send-email", error = err.to_string()),
+                    fl!("message-content", content = body)
+                );
+            }
+        }
+    }
+
+    pub async fn run(mut self, subsys: SubsystemHandle) -> Result<()> {
+        let mut batch = Vec::new();
+        // When the current batch is due, None while there's nothing to send
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            tokio::select! {
+                msg = self.rx.recv() => match msg {
+                    Some(msg) => {
+                        batch.push(msg);
+                        deadline.get_or_insert_with(|| Instant::now() + self.batch_window);
+                        if batch.len() >= MAX_BATCH_SIZE {
+                            deadline = None;
+                            self.send(std::mem::take(&mut batch)).await;
+                        }
+                    }
+                    None => break,
+                },
+                _ = async { sleep_until(deadline.unwrap()).await }, if deadline.is_some() => {
+                    deadline = None;
+                    self.send(std::mem::take(&mut batch)).await;
+                }
+                _ = subsys.on_shutdown_requested() => break,
+            }
+        }
+
+        // Don't lose what was already collected
+        if !batch.is_empty() {
+            self.send(batch).await;
+        }
+        debug!("{}", fl!("email-shutdown"));
+        Ok(())
+    }
+}
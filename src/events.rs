@@ -0,0 +1,105 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::MessageData;
+
+// Node info announced by a mesh node and heard by a gateway
+#[derive(Clone, Debug)]
+pub struct NodeInfoEvent {
+    pub id: Option<u32>,
+    pub node_id: u32,
+    pub shortname: String,
+    pub longname: String,
+    pub via: String,
+    pub received_at: u64,
+}
+
+// Telemetry received from a mesh node, or sent by the gateway itself
+#[derive(Clone, Debug)]
+pub struct TelemetryEvent {
+    pub id: Option<u32>,
+    pub from_id: Option<u32>,
+    // The sender name as the gateway logged it, None for the gateway's own telemetry
+    pub sender: Option<String>,
+    pub metrics: BTreeMap<String, f64>,
+    pub via: String,
+    pub received_at: u64,
+}
+
+impl TelemetryEvent {
+    pub fn is_local(&self) -> bool {
+        self.sender.is_none()
+    }
+}
+
+// Why a text message wasn't forwarded
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IgnoreReason {
+    RangeTest,
+    // The packet header line for the message was never seen
+    NoPacketInfo,
+    Mqtt,
+    // The channel or DM settings don't forward it
+    Filtered,
+}
+
+impl IgnoreReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            IgnoreReason::RangeTest => "range_test",
+            IgnoreReason::NoPacketInfo => "no_packet_info",
+            IgnoreReason::Mqtt => "mqtt",
+            IgnoreReason::Filtered => "filtered",
+        }
+    }
+}
+
+// A text message that was received but not forwarded, only archived
+#[derive(Clone, Debug)]
+pub struct IgnoredEvent {
+    pub id: u32,
+    pub from_id: u32,
+    pub text: String,
+    pub reason: IgnoreReason,
+    pub to: Option<u32>,
+    pub channel: Option<u32>,
+    pub via: String,
+    pub received_at: u64,
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Text(MessageData),
+    NodeInfo(NodeInfoEvent),
+    Telemetry(TelemetryEvent),
+    Ignored(IgnoredEvent),
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Text(_) => "text",
+            Event::NodeInfo(_) => "nodeinfo",
+            Event::Telemetry(_) => "telemetry",
+            Event::Ignored(_) => "ignored",
+        }
+    }
+
+    // Who the event came from, for logs
+    pub fn sender_name(&self) -> String {
+        match self {
+            Event::Text(data) => data.from.clone(),
+            Event::NodeInfo(info) => info.longname.clone(),
+            Event::Telemetry(telemetry) => telemetry
+                .sender
+                .clone()
+                .unwrap_or_else(|| telemetry.via.clone()),
+            Event::Ignored(ignored) => format!("0x{:08x}", ignored.from_id),
+        }
+    }
+}
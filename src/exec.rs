@@ -0,0 +1,235 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Result, bail};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::process::{Output, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use crate::notifications::{MessageKind, route_matches};
+use crate::{MessageData, fl};
+
+const DEFAULT_TIMEOUT: u64 = 10;
+const DEFAULT_MAX_CONCURRENT: usize = 4;
+
+// Program output in logs is cut to this many characters
+const OUTPUT_LOG_LIMIT: usize = 500;
+
+// An `[[exec]]` hook of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExecConfig {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    timeout: Option<u64>,
+    max_concurrent: Option<usize>,
+    #[serde(default)]
+    messages: MessageKind,
+    #[serde(default)]
+    channels: Vec<u32>,
+}
+
+#[derive(Clone)]
+struct ExecHook {
+    command: String,
+    args: Vec<String>,
+    timeout: Duration,
+    // Messages beyond the limit wait for a running program to finish
+    permits: Arc<Semaphore>,
+    messages: MessageKind,
+    channels: Vec<u32>,
+}
+
+#[derive(Clone)]
+pub struct ExecSink {
+    hooks: Vec<ExecHook>,
+}
+
+fn truncate_output(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    let output = output.trim();
+    if output.chars().count() <= OUTPUT_LOG_LIMIT {
+        return output.to_string();
+    }
+    let mut truncated: String = output.chars().take(OUTPUT_LOG_LIMIT).collect();
+    truncated.push('…');
+    truncated
+}
+
+// Metadata for programs that don't want to parse JSON
+fn environment(data: &MessageData) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("EMTT_EVENT", "text".to_string()),
+        ("EMTT_MESSAGE_ID", data.id.to_string()),
+        ("EMTT_FROM", data.from.clone()),
+        ("EMTT_FROM_ID", format!("!{:08x}", data.from_id)),
+        ("EMTT_TO_ID", format!("!{:08x}", data.to)),
+        ("EMTT_VIA", data.via.clone()),
+        ("EMTT_CHANNEL", data.channel.to_string()),
+        ("EMTT_DIRECT", data.is_direct().to_string()),
+        ("EMTT_RECEIVED_AT", data.received_at.to_string()),
+    ];
+    if let Some(snr) = data.snr {
+        env.push(("EMTT_SNR", snr.to_string()));
+    }
+    if let Some(rssi) = data.rssi {
+        env.push(("EMTT_RSSI", rssi.to_string()));
+    }
+    if let Some(hops_away) = data.hops_away {
+        env.push(("EMTT_HOPS_AWAY", hops_away.to_string()));
+    }
+    env
+}
+
+impl ExecHook {
+    fn compile(config: &ExecConfig) -> Result<Self> {
+        if config.command.is_empty() {
+            bail!("Empty exec command");
+        }
+        let max_concurrent = config.max_concurrent.unwrap_or(DEFAULT_MAX_CONCURRENT);
+        if max_concurrent == 0 {
+            bail!("max_concurrent for {} must be at least 1", config.command);
+        }
+
+        Ok(Self {
+            command: config.command.clone(),
+            args: config.args.clone(),
+            timeout: Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            messages: config.messages,
+            channels: config.channels.clone(),
+        })
+    }
+
+    async fn spawn(&self, input: &[u8], data: &MessageData) -> std::io::Result<Output> {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .envs(environment(data))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // A timed out program is killed when its future is dropped
+            .kill_on_drop(true)
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // Programs are free to ignore stdin, so a closed pipe is fine
+            match stdin.write_all(input).await {
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+                res => res?,
+            }
+        }
+
+        child.wait_with_output().await
+    }
+
+    async fn run(&self, data: &MessageData) {
+        let input = match serde_json::to_vec(data) {
+            Ok(i) => i,
+            Err(e) => {
+                warn!("{}", fl!("failed-to-render", error = e.to_string()));
+                return;
+            }
+        };
+
+        let Ok(_permit) = self.permits.acquire().await else {
+            return;
+        };
+
+        let output = match tokio::time::timeout(self.timeout, self.spawn(&input, data)).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => {
+                metrics::counter!("emtt_sink_sends_total", "sink" => "exec", "result" => "failure")
+                    .increment(1);
+                warn!(
+                    "{}",
+                    fl!(
+                        "failed-to-exec",
+                        command = self.command.clone(),
+                        error = e.to_string()
+                    )
+                );
+                return;
+            }
+            Err(_) => {
+                metrics::counter!("emtt_sink_sends_total", "sink" => "exec", "result" => "failure")
+                    .increment(1);
+                warn!(
+                    "{}",
+                    fl!(
+                        "exec-timed-out",
+                        command = self.command.clone(),
+                        timeout = self.timeout.as_secs()
+                    )
+                );
+                return;
+            }
+        };
+
+        let code = output
+            .status
+            .code()
+            .map_or_else(|| output.status.to_string(), |c| c.to_string());
+        if output.status.success() {
+            metrics::counter!("emtt_sink_sends_total", "sink" => "exec", "result" => "success")
+                .increment(1);
+            debug!(
+                "{}",
+                fl!(
+                    "exec-finished",
+                    command = self.command.clone(),
+                    from = data.from.clone(),
+                    output = truncate_output(&output.stdout)
+                )
+            );
+        } else {
+            metrics::counter!("emtt_sink_sends_total", "sink" => "exec", "result" => "failure")
+                .increment(1);
+            warn!(
+                "{}",
+                fl!(
+                    "exec-failed",
+                    command = self.command.clone(),
+                    code = code,
+                    output = truncate_output(&output.stderr)
+                )
+            );
+        }
+    }
+}
+
+impl ExecSink {
+    pub fn compile(configs: &[ExecConfig]) -> Result<Self> {
+        let hooks = configs
+            .iter()
+            .map(ExecHook::compile)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { hooks })
+    }
+
+    pub fn hook_count(&self) -> usize {
+        self.hooks.len()
+    }
+
+    // Each hook runs on its own, a slow program doesn't hold up the others
+    pub fn deliver(&self, data: &MessageData) {
+        for hook in self
+            .hooks
+            .iter()
+            .filter(|h| route_matches(h.messages, &h.channels, data))
+        {
+            let hook = hook.clone();
+            let data = data.clone();
+            tokio::spawn(async move {
+                hook.run(&data).await;
+            });
+        }
+    }
+}
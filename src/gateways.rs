@@ -0,0 +1,669 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local};
+use log::{debug, info, warn};
+use minijinja::context;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use teloxide::Bot;
+use tokio::sync::Notify;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+use crate::events::TelemetryEvent;
+use crate::{ParseModeOpt, fl, telegram};
+
+const DEFAULT_SILENCE_TIMEOUT: u64 = 600;
+
+// How often gateways are checked for silence
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+// How far a reading has to get back past its threshold before it can alert again
+const HYSTERESIS: f64 = 5.0;
+
+// Meshtastic reports battery_level=101 when the node runs on external power
+const EXTERNAL_POWER: f64 = 100.0;
+
+const DEFAULT_SILENCE_TEMPLATE: &str =
+    "⚠️ Gateway {{ gateway }} has been silent for {{ minutes }} min, last heard at {{ last_seen }}";
+const DEFAULT_RECOVERY_TEMPLATE: &str =
+    "✅ Gateway {{ gateway }} is back after {{ minutes }} min of silence";
+const DEFAULT_LOW_BATTERY_TEMPLATE: &str =
+    "🪫 Gateway {{ gateway }} battery is low: {{ battery_level }}%, {{ voltage }} V";
+const DEFAULT_CHANNEL_UTILIZATION_TEMPLATE: &str = "📶 Gateway {{ gateway }} channel utilization is {{ channel_utilization }}%, air util TX {{ air_util_tx }}%";
+
+// The `[gateways]` section of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewaysConfig {
+    #[serde(default)]
+    chat_ids: Vec<i64>,
+    silence_timeout: Option<u64>,
+    // Gateways that raise the alarm even if they're never heard after startup
+    #[serde(default)]
+    expected: Vec<String>,
+    silence_template: Option<String>,
+    recovery_template: Option<String>,
+    low_battery: Option<f64>,
+    low_battery_template: Option<String>,
+    max_channel_utilization: Option<f64>,
+    channel_utilization_template: Option<String>,
+}
+
+// The latest device telemetry a gateway logged about itself
+#[derive(Clone, Debug, Default)]
+pub struct DeviceMetrics {
+    pub battery_level: Option<f64>,
+    pub voltage: Option<f64>,
+    pub channel_utilization: Option<f64>,
+    pub air_util_tx: Option<f64>,
+    pub uptime: Option<u64>,
+}
+
+impl DeviceMetrics {
+    fn from_telemetry(metrics: &BTreeMap<String, f64>) -> Option<Self> {
+        let device = Self {
+            battery_level: metrics.get("battery_level").copied(),
+            voltage: metrics.get("voltage").copied(),
+            channel_utilization: metrics.get("channel_utilization").copied(),
+            air_util_tx: metrics.get("air_util_tx").copied(),
+            uptime: metrics.get("uptime_seconds").map(|u| *u as u64),
+        };
+        // Environment and other telemetry have none of these
+        (device.battery_level.is_some()
+            || device.voltage.is_some()
+            || device.channel_utilization.is_some()
+            || device.air_util_tx.is_some())
+        .then_some(device)
+    }
+
+    pub fn on_external_power(&self) -> bool {
+        self.battery_level.is_some_and(|b| b > EXTERNAL_POWER)
+    }
+}
+
+struct GatewayState {
+    last_seen: SystemTime,
+    // The last datagram before the alarm was sent, None while the gateway is fine
+    silent_since: Option<SystemTime>,
+    device: Option<DeviceMetrics>,
+    low_battery: bool,
+    busy_channel: bool,
+}
+
+impl GatewayState {
+    fn new(last_seen: SystemTime) -> Self {
+        Self {
+            last_seen,
+            silent_since: None,
+            device: None,
+            low_battery: false,
+            busy_channel: false,
+        }
+    }
+}
+
+// What the bot shows about a gateway
+pub struct GatewayStatus {
+    pub ident: String,
+    pub last_seen: SystemTime,
+    pub silent: bool,
+    pub device: Option<DeviceMetrics>,
+}
+
+// Tracks when each gateway was last heard and how it's doing, shared with the syslog server and the bot
+#[derive(Clone, Default)]
+pub struct GatewayMonitor {
+    gateways: Arc<Mutex<HashMap<String, GatewayState>>>,
+    // Wakes the watcher as soon as something worth a message may have happened
+    changed: Arc<Notify>,
+}
+
+// Sends silence alarms, recovery messages and device alerts, runs as a subsystem
+pub struct GatewayWatcher {
+    gateways: Arc<Mutex<HashMap<String, GatewayState>>>,
+    changed: Arc<Notify>,
+    chat_ids: Vec<i64>,
+    timeout: Duration,
+    silence_template: String,
+    recovery_template: String,
+    low_battery: Option<f64>,
+    low_battery_template: String,
+    max_channel_utilization: Option<f64>,
+    channel_utilization_template: String,
+    parse_mode: ParseModeOpt,
+}
+
+enum Change {
+    Silent {
+        gateway: String,
+        last_seen: SystemTime,
+    },
+    Recovered {
+        gateway: String,
+        silent_for: Duration,
+    },
+    LowBattery {
+        gateway: String,
+        device: DeviceMetrics,
+    },
+    BusyChannel {
+        gateway: String,
+        device: DeviceMetrics,
+    },
+}
+
+fn minutes(duration: Duration) -> u64 {
+    duration.as_secs() / 60
+}
+
+fn round(value: Option<f64>) -> Option<f64> {
+    value.map(|v| (v * 10.0).round() / 10.0)
+}
+
+fn template(
+    value: &Option<String>,
+    default: &str,
+    env: &minijinja::Environment,
+    name: &str,
+) -> Result<String> {
+    let template = value
+        .clone()
+        .map(crate::unescape_template)
+        .unwrap_or_else(|| default.to_string());
+    env.template_from_str(&template)
+        .with_context(|| format!("Invalid gateway {} template", name))?;
+    Ok(template)
+}
+
+impl GatewayMonitor {
+    pub fn watcher(
+        &self,
+        config: &GatewaysConfig,
+        default_chat_ids: &[i64],
+        parse_mode: ParseModeOpt,
+    ) -> Result<GatewayWatcher> {
+        let chat_ids = if config.chat_ids.is_empty() {
+            default_chat_ids.to_vec()
+        } else {
+            config.chat_ids.clone()
+        };
+        if chat_ids.is_empty() {
+            bail!("No chat IDs for gateway alarms");
+        }
+
+        let timeout = config.silence_timeout.unwrap_or(DEFAULT_SILENCE_TIMEOUT);
+        if timeout == 0 {
+            bail!("silence_timeout must be at least 1 second");
+        }
+
+        let env = crate::create_template_env(parse_mode);
+        let silence_template = template(
+            &config.silence_template,
+            DEFAULT_SILENCE_TEMPLATE,
+            &env,
+            "silence",
+        )?;
+        let recovery_template = template(
+            &config.recovery_template,
+            DEFAULT_RECOVERY_TEMPLATE,
+            &env,
+            "recovery",
+        )?;
+        let low_battery_template = template(
+            &config.low_battery_template,
+            DEFAULT_LOW_BATTERY_TEMPLATE,
+            &env,
+            "low battery",
+        )?;
+        let channel_utilization_template = template(
+            &config.channel_utilization_template,
+            DEFAULT_CHANNEL_UTILIZATION_TEMPLATE,
+            &env,
+            "channel utilization",
+        )?;
+
+        // Expected gateways count as heard at startup
+        let now = SystemTime::now();
+        let mut gateways = self.gateways.lock().unwrap();
+        for ident in &config.expected {
+            gateways
+                .entry(ident.clone())
+                .or_insert_with(|| GatewayState::new(now));
+        }
+
+        Ok(GatewayWatcher {
+            gateways: self.gateways.clone(),
+            changed: self.changed.clone(),
+            chat_ids,
+            timeout: Duration::from_secs(timeout),
+            silence_template,
+            recovery_template,
+            low_battery: config.low_battery,
+            low_battery_template,
+            max_channel_utilization: config.max_channel_utilization,
+            channel_utilization_template,
+            parse_mode,
+        })
+    }
+
+    pub fn record(&self, ident: &str) {
+        let mut gateways = self.gateways.lock().unwrap();
+        let state = gateways
+            .entry(ident.to_string())
+            .or_insert_with(|| GatewayState::new(SystemTime::now()));
+        state.last_seen = SystemTime::now();
+        if state.silent_since.is_some() {
+            self.changed.notify_one();
+        }
+    }
+
+    // Only the gateway's own telemetry describes the gateway
+    pub fn record_telemetry(&self, telemetry: &TelemetryEvent) {
+        if !telemetry.is_local() {
+            return;
+        }
+        let Some(device) = DeviceMetrics::from_telemetry(&telemetry.metrics) else {
+            return;
+        };
+
+        let gateway = telemetry.via.clone();
+        let gauges = [
+            ("emtt_gateway_battery_level_percent", device.battery_level),
+            ("emtt_gateway_voltage_volts", device.voltage),
+            (
+                "emtt_gateway_channel_utilization_percent",
+                device.channel_utilization,
+            ),
+            ("emtt_gateway_air_util_tx_percent", device.air_util_tx),
+            (
+                "emtt_gateway_uptime_seconds",
+                device.uptime.map(|u| u as f64),
+            ),
+        ];
+        for (name, value) in gauges {
+            if let Some(value) = value {
+                metrics::gauge!(name, "gateway" => gateway.clone()).set(value);
+            }
+        }
+
+        let mut gateways = self.gateways.lock().unwrap();
+        gateways
+            .entry(gateway)
+            .or_insert_with(|| GatewayState::new(SystemTime::now()))
+            .device = Some(device);
+        self.changed.notify_one();
+    }
+
+    pub fn statuses(&self) -> Vec<GatewayStatus> {
+        let gateways = self.gateways.lock().unwrap();
+        let mut statuses: Vec<GatewayStatus> = gateways
+            .iter()
+            .map(|(ident, state)| GatewayStatus {
+                ident: ident.clone(),
+                last_seen: state.last_seen,
+                silent: state.silent_since.is_some(),
+                device: state.device.clone(),
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.ident.cmp(&b.ident));
+        statuses
+    }
+}
+
+impl GatewayWatcher {
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn changes(&self) -> Vec<Change> {
+        let now = SystemTime::now();
+        let mut gateways = self.gateways.lock().unwrap();
+        let mut changes = Vec::new();
+
+        for (ident, state) in gateways.iter_mut() {
+            let quiet_for = now.duration_since(state.last_seen).unwrap_or_default();
+            match state.silent_since {
+                None if quiet_for > self.timeout => {
+                    state.silent_since = Some(state.last_seen);
+                    changes.push(Change::Silent {
+                        gateway: ident.clone(),
+                        last_seen: state.last_seen,
+                    });
+                }
+                Some(since) if state.last_seen > since => {
+                    state.silent_since = None;
+                    changes.push(Change::Recovered {
+                        gateway: ident.clone(),
+                        silent_for: state.last_seen.duration_since(since).unwrap_or_default(),
+                    });
+                }
+                _ => {}
+            }
+
+            let Some(device) = &state.device else {
+                continue;
+            };
+
+            if let Some(threshold) = self.low_battery
+                && let Some(battery) = device.battery_level
+                && !device.on_external_power()
+            {
+                if !state.low_battery && battery < threshold {
+                    state.low_battery = true;
+                    changes.push(Change::LowBattery {
+                        gateway: ident.clone(),
+                        device: device.clone(),
+                    });
+                } else if state.low_battery && battery >= threshold + HYSTERESIS {
+                    state.low_battery = false;
+                }
+            }
+
+            if let Some(limit) = self.max_channel_utilization
+                && let Some(utilization) = device.channel_utilization
+            {
+                if !state.busy_channel && utilization > limit {
+                    state.busy_channel = true;
+                    changes.push(Change::BusyChannel {
+                        gateway: ident.clone(),
+                        device: device.clone(),
+                    });
+                } else if state.busy_channel && utilization <= limit - HYSTERESIS {
+                    state.busy_channel = false;
+                }
+            }
+        }
+        changes
+    }
+
+    fn render(&self, change: &Change) -> Result<String, minijinja::Error> {
+        let env = crate::create_template_env(self.parse_mode);
+        match change {
+            Change::Silent { gateway, last_seen } => {
+                let silent_for = SystemTime::now()
+                    .duration_since(*last_seen)
+                    .unwrap_or_default();
+                env.render_str(
+                    &self.silence_template,
+                    context! {
+                        gateway => gateway,
+                        minutes => minutes(silent_for),
+                        last_seen => DateTime::<Local>::from(*last_seen)
+                            .format("%Y-%m-%d %H:%M:%S")
+                            .to_string(),
+                    },
+                )
+            }
+            Change::Recovered {
+                gateway,
+                silent_for,
+            } => env.render_str(
+                &self.recovery_template,
+                context! {
+                    gateway => gateway,
+                    minutes => minutes(*silent_for),
+                },
+            ),
+            Change::LowBattery { gateway, device } | Change::BusyChannel { gateway, device } => {
+                let template = match change {
+                    Change::LowBattery { .. } => &self.low_battery_template,
+                    _ => &self.channel_utilization_template,
+                };
+                env.render_str(
+                    template,
+                    context! {
+                        gateway => gateway,
+                        battery_level => device.battery_level.map(|b| b.round() as i64),
+                        voltage => round(device.voltage),
+                        channel_utilization => round(device.channel_utilization),
+                        air_util_tx => round(device.air_util_tx),
+                    },
+                )
+            }
+        }
+    }
+
+    async fn notify(&self, bot: &Bot, change: &Change) {
+        match change {
+            Change::Silent { gateway, .. } => {
+                warn!("{}", fl!("gateway-silent", gateway = gateway.clone()))
+            }
+            Change::Recovered { gateway, .. } => {
+                info!("{}", fl!("gateway-recovered", gateway = gateway.clone()))
+            }
+            Change::LowBattery { gateway, device } => warn!(
+                "{}",
+                fl!(
+                    "gateway-low-battery",
+                    gateway = gateway.clone(),
+                    battery = device.battery_level.unwrap_or_default()
+                )
+            ),
+            Change::BusyChannel { gateway, device } => warn!(
+                "{}",
+                fl!(
+                    "gateway-busy-channel",
+                    gateway = gateway.clone(),
+                    utilization = device.channel_utilization.unwrap_or_default()
+                )
+            ),
+        }
+
+        let rendered = match self.render(change) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{}", fl!("failed-to-render", error = e.to_string()));
+                return;
+            }
+        };
+
+        for chat_id in &self.chat_ids {
+            if let Err(err) = telegram::send_message(
+                bot,
+                *chat_id,
+                &rendered,
+                self.parse_mode.telegram(),
+                false,
+                None,
+                None,
+            )
+            .await
+            {
+                warn!(
+                    "{}\n{}",
+                    fl!("failed-to-send", error = err.to_string()),
+                    fl!("message-content", content = rendered.clone())
+                );
+            }
+        }
+    }
+
+    pub async fn run(self, subsys: SubsystemHandle, bot: Bot) -> Result<()> {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+                _ = self.changed.notified() => {}
+                _ = subsys.on_shutdown_requested() => break,
+            }
+
+            for change in self.changes() {
+                self.notify(&bot, &change).await;
+            }
+        }
+        debug!("{}", fl!("gateway-monitor-shutdown"));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GATEWAY: &str = "gw1";
+
+    fn watch() -> (GatewayMonitor, GatewayWatcher) {
+        let monitor = GatewayMonitor::default();
+        let config = GatewaysConfig {
+            chat_ids: vec![1],
+            silence_timeout: Some(600),
+            expected: Vec::new(),
+            silence_template: None,
+            recovery_template: None,
+            low_battery: Some(20.0),
+            low_battery_template: None,
+            max_channel_utilization: Some(50.0),
+            channel_utilization_template: None,
+        };
+        let watcher = monitor.watcher(&config, &[], ParseModeOpt::None).unwrap();
+        (monitor, watcher)
+    }
+
+    fn heard_ago(monitor: &GatewayMonitor, seconds: u64) {
+        monitor.record(GATEWAY);
+        monitor
+            .gateways
+            .lock()
+            .unwrap()
+            .get_mut(GATEWAY)
+            .unwrap()
+            .last_seen = SystemTime::now() - Duration::from_secs(seconds);
+    }
+
+    fn report(monitor: &GatewayMonitor, battery_level: f64, channel_utilization: f64) {
+        monitor.record_telemetry(&TelemetryEvent {
+            id: None,
+            from_id: None,
+            sender: None,
+            metrics: BTreeMap::from([
+                ("battery_level".to_string(), battery_level),
+                ("channel_utilization".to_string(), channel_utilization),
+            ]),
+            via: GATEWAY.to_string(),
+            received_at: 0,
+        });
+    }
+
+    fn kinds(watcher: &GatewayWatcher) -> Vec<&'static str> {
+        watcher
+            .changes()
+            .iter()
+            .map(|change| match change {
+                Change::Silent { .. } => "silent",
+                Change::Recovered { .. } => "recovered",
+                Change::LowBattery { .. } => "low_battery",
+                Change::BusyChannel { .. } => "busy_channel",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alarms_once_after_the_silence_timeout() {
+        let (monitor, watcher) = watch();
+        heard_ago(&monitor, 590);
+        assert!(kinds(&watcher).is_empty());
+
+        heard_ago(&monitor, 610);
+        assert_eq!(kinds(&watcher), ["silent"]);
+        assert!(kinds(&watcher).is_empty());
+        assert!(monitor.statuses()[0].silent);
+    }
+
+    #[test]
+    fn recovers_once_when_heard_again() {
+        let (monitor, watcher) = watch();
+        heard_ago(&monitor, 900);
+        assert_eq!(kinds(&watcher), ["silent"]);
+
+        monitor.record(GATEWAY);
+        let changes = watcher.changes();
+        assert_eq!(changes.len(), 1);
+        match &changes[0] {
+            Change::Recovered {
+                gateway,
+                silent_for,
+            } => {
+                assert_eq!(gateway, GATEWAY);
+                assert!(minutes(*silent_for) >= 15);
+            }
+            _ => panic!("expected a recovery"),
+        }
+
+        monitor.record(GATEWAY);
+        assert!(kinds(&watcher).is_empty());
+        assert!(!monitor.statuses()[0].silent);
+    }
+
+    #[test]
+    fn low_battery_alerts_with_hysteresis() {
+        let (monitor, watcher) = watch();
+        for (battery, expected) in [
+            (30.0, &[][..]),
+            (19.0, &["low_battery"][..]),
+            (15.0, &[]),
+            // Back above the threshold, but not by the hysteresis
+            (22.0, &[]),
+            (19.0, &[]),
+            (25.0, &[]),
+            (19.0, &["low_battery"]),
+        ] {
+            report(&monitor, battery, 10.0);
+            assert_eq!(kinds(&watcher), expected, "battery {battery}");
+        }
+    }
+
+    #[test]
+    fn external_power_is_never_low_battery() {
+        let (monitor, watcher) = watch();
+        report(&monitor, 101.0, 10.0);
+        assert!(kinds(&watcher).is_empty());
+    }
+
+    #[test]
+    fn busy_channel_alerts_with_hysteresis() {
+        let (monitor, watcher) = watch();
+        for (utilization, expected) in [
+            (40.0, &[][..]),
+            (51.0, &["busy_channel"][..]),
+            (70.0, &[]),
+            // Back below the limit, but not by the hysteresis
+            (48.0, &[]),
+            (52.0, &[]),
+            (45.0, &[]),
+            (51.0, &["busy_channel"]),
+        ] {
+            report(&monitor, 90.0, utilization);
+            assert_eq!(kinds(&watcher), expected, "utilization {utilization}");
+        }
+    }
+
+    #[test]
+    fn reads_device_metrics_from_logged_telemetry() {
+        let (sender, metrics) = crate::syslog::parse_telemetry(
+            "Send: air_util_tx=0.05, channel_utilization=3.1, battery_level=101, voltage=4.2, uptime_seconds=3600",
+        )
+        .unwrap();
+        assert_eq!(sender, None);
+
+        let device = DeviceMetrics::from_telemetry(&metrics).unwrap();
+        assert_eq!(device.uptime, Some(3600));
+        assert_eq!(device.battery_level, Some(101.0));
+        assert_eq!(device.voltage, Some(4.2));
+        assert_eq!(device.channel_utilization, Some(3.1));
+        assert_eq!(device.air_util_tx, Some(0.05));
+        assert!(device.on_external_power());
+    }
+
+    #[test]
+    fn ignores_environment_telemetry() {
+        let (_, metrics) = crate::syslog::parse_telemetry(
+            "Send: temperature=-3.5, relative_humidity=80, barometric_pressure=1013.2",
+        )
+        .unwrap();
+        assert!(DeviceMetrics::from_telemetry(&metrics).is_none());
+    }
+}
@@ -0,0 +1,300 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chrono::{DateTime, SecondsFormat};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::MessageData;
+use crate::events::Event;
+use crate::mqtt::MqttSink;
+
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+const DEFAULT_BASE_TOPIC: &str = "emtt";
+
+// Home Assistant rejects states longer than this
+const STATE_MAX_CHARS: usize = 255;
+
+// The `[mqtt.homeassistant]` section of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HomeAssistantConfig {
+    discovery_prefix: Option<String>,
+    base_topic: Option<String>,
+}
+
+struct Sensor {
+    key: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    measurement: bool,
+}
+
+const SENSORS: &[Sensor] = &[
+    Sensor {
+        key: "last_message",
+        name: "Last message",
+        device_class: None,
+        unit: None,
+        measurement: false,
+    },
+    Sensor {
+        key: "snr",
+        name: "SNR",
+        device_class: Some("signal_strength"),
+        unit: Some("dB"),
+        measurement: true,
+    },
+    Sensor {
+        key: "rssi",
+        name: "RSSI",
+        device_class: Some("signal_strength"),
+        unit: Some("dBm"),
+        measurement: true,
+    },
+    Sensor {
+        key: "hops_away",
+        name: "Hops away",
+        device_class: None,
+        unit: None,
+        measurement: true,
+    },
+    Sensor {
+        key: "last_seen",
+        name: "Last seen",
+        device_class: Some("timestamp"),
+        unit: None,
+        measurement: false,
+    },
+];
+
+// A gateway ident or a mesh node, shown as a device in Home Assistant
+struct Device {
+    // Used in unique IDs and topics, so only safe characters
+    object_id: String,
+    name: String,
+    model: &'static str,
+    // State and events are published under this topic
+    topic: String,
+}
+
+impl Device {
+    fn state_topic(&self) -> String {
+        format!("{}/state", self.topic)
+    }
+
+    fn event_topic(&self) -> String {
+        format!("{}/message", self.topic)
+    }
+}
+
+#[derive(Clone)]
+pub struct HomeAssistant {
+    discovery_prefix: String,
+    base_topic: String,
+    // Device names already announced, discovery is repeated only when a name changes
+    announced: Arc<Mutex<HashMap<String, String>>>,
+}
+
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn truncate(text: &str) -> String {
+    if text.chars().count() <= STATE_MAX_CHARS {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(STATE_MAX_CHARS - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn timestamp(secs: u64) -> String {
+    DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+impl HomeAssistant {
+    pub fn new(config: &HomeAssistantConfig) -> Self {
+        Self {
+            discovery_prefix: config
+                .discovery_prefix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_DISCOVERY_PREFIX.to_string()),
+            base_topic: config
+                .base_topic
+                .clone()
+                .unwrap_or_else(|| DEFAULT_BASE_TOPIC.to_string()),
+            announced: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn gateway(&self, ident: &str) -> Device {
+        let ident_id = sanitize(ident);
+        Device {
+            object_id: format!("emtt_gw_{}", ident_id),
+            name: ident.to_string(),
+            model: "Gateway",
+            topic: format!("{}/gateways/{}", self.base_topic, ident_id),
+        }
+    }
+
+    fn node(&self, node_id: u32, name: Option<&str>) -> Device {
+        let hex = format!("{:08x}", node_id);
+        Device {
+            object_id: format!("emtt_node_{}", hex),
+            name: name.map_or_else(|| format!("!{}", hex), str::to_string),
+            model: "Node",
+            topic: format!("{}/nodes/{}", self.base_topic, hex),
+        }
+    }
+
+    fn device_info(device: &Device) -> Value {
+        json!({
+            "identifiers": [device.object_id],
+            "name": device.name,
+            "manufacturer": "Meshtastic",
+            "model": device.model,
+        })
+    }
+
+    fn origin() -> Value {
+        json!({
+            "name": "EMtT",
+            "sw": env!("CARGO_PKG_VERSION"),
+            "url": env!("CARGO_PKG_REPOSITORY"),
+        })
+    }
+
+    // Publishes retained discovery configs, so Home Assistant picks them up after a restart too.
+    // A device only counts as announced once every config is queued, otherwise it's retried
+    // with the next message.
+    fn announce(&self, mqtt: &MqttSink, device: &Device, with_event: bool) {
+        if self.announced.lock().unwrap().get(&device.object_id) == Some(&device.name) {
+            return;
+        }
+
+        let mut queued = true;
+
+        for sensor in SENSORS {
+            let mut config = json!({
+                "name": sensor.name,
+                "unique_id": format!("{}_{}", device.object_id, sensor.key),
+                "state_topic": device.state_topic(),
+                "value_template": format!("{{{{ value_json.{} }}}}", sensor.key),
+                "device": Self::device_info(device),
+                "origin": Self::origin(),
+            });
+            if let Some(device_class) = sensor.device_class {
+                config["device_class"] = json!(device_class);
+            }
+            if let Some(unit) = sensor.unit {
+                config["unit_of_measurement"] = json!(unit);
+            }
+            if sensor.measurement {
+                config["state_class"] = json!("measurement");
+            }
+            let topic = format!(
+                "{}/sensor/{}/{}/config",
+                self.discovery_prefix, device.object_id, sensor.key
+            );
+            queued &= mqtt.send(topic, config.to_string(), true);
+        }
+
+        if with_event {
+            let config = json!({
+                "name": "Message",
+                "unique_id": format!("{}_message", device.object_id),
+                "state_topic": device.event_topic(),
+                "event_types": ["text"],
+                "device": Self::device_info(device),
+                "origin": Self::origin(),
+            });
+            let topic = format!(
+                "{}/event/{}/message/config",
+                self.discovery_prefix, device.object_id
+            );
+            queued &= mqtt.send(topic, config.to_string(), true);
+        }
+
+        if queued {
+            self.announced
+                .lock()
+                .unwrap()
+                .insert(device.object_id.clone(), device.name.clone());
+        }
+    }
+
+    fn update_state(
+        &self,
+        mqtt: &MqttSink,
+        device: &Device,
+        last_message: &str,
+        data: &MessageData,
+    ) {
+        let state = json!({
+            "last_message": truncate(last_message),
+            "snr": data.snr,
+            "rssi": data.rssi,
+            "hops_away": data.hops_away,
+            "last_seen": timestamp(data.received_at),
+        });
+        mqtt.send(device.state_topic(), state.to_string(), true);
+    }
+
+    pub fn publish(&self, mqtt: &MqttSink, event: &Event) {
+        match event {
+            Event::Text(data) => {
+                let gateway = self.gateway(&data.via);
+                self.announce(mqtt, &gateway, true);
+                self.update_state(
+                    mqtt,
+                    &gateway,
+                    &format!("{}: {}", data.from, data.text),
+                    data,
+                );
+
+                // Messages sent by the gateway itself have no node ID
+                if data.from_id != 0 {
+                    let node = self.node(data.from_id, data.longname.as_deref());
+                    self.announce(mqtt, &node, false);
+                    self.update_state(mqtt, &node, &data.text, data);
+                }
+
+                // Event entities take the event type plus any attributes
+                let message = json!({
+                    "event_type": "text",
+                    "from": data.from,
+                    "from_id": format!("!{:08x}", data.from_id),
+                    "to": format!("!{:08x}", data.to),
+                    "direct": data.is_direct(),
+                    "channel": data.channel,
+                    "text": data.text,
+                    "snr": data.snr,
+                    "rssi": data.rssi,
+                    "hops_away": data.hops_away,
+                    "is_name_conflict": data.is_name_conflict,
+                });
+                mqtt.send(gateway.event_topic(), message.to_string(), false);
+            }
+            Event::NodeInfo(info) => {
+                let node = self.node(info.node_id, Some(&info.longname));
+                self.announce(mqtt, &node, false);
+            }
+            Event::Telemetry(_) | Event::Ignored(_) => {}
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use log::warn;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::fl;
+
+// Response bodies in logs are cut to this many characters
+const BODY_LOG_LIMIT: usize = 500;
+
+// However many retries are configured, they're never spaced further apart than this
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub timeout: Duration,
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    // Doubles with every attempt, up to MAX_BACKOFF
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .checked_mul(2u32.saturating_pow(attempt))
+            .map_or(MAX_BACKOFF, |d| d.min(MAX_BACKOFF))
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    Request(reqwest::Error),
+    Status(StatusCode, String),
+}
+
+impl SendError {
+    // Client errors won't go away on their own, except for rate limiting
+    fn is_retryable(&self) -> bool {
+        match self {
+            SendError::Request(_) => true,
+            SendError::Status(status, _) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+        }
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Request(e) => write!(f, "{}", e),
+            SendError::Status(status, body) if body.is_empty() => write!(f, "HTTP {}", status),
+            SendError::Status(status, body) => write!(f, "HTTP {}: {}", status, body),
+        }
+    }
+}
+
+fn truncate_body(body: String) -> String {
+    if body.chars().count() <= BODY_LOG_LIMIT {
+        return body;
+    }
+    let mut truncated: String = body.chars().take(BODY_LOG_LIMIT).collect();
+    truncated.push('…');
+    truncated
+}
+
+// Runs `operation` until it succeeds, fails for good or runs out of retries
+pub async fn retry<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    mut operation: F,
+    is_retryable: impl Fn(&E) -> bool,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: fmt::Display,
+{
+    let mut attempt = 0;
+    loop {
+        let err = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        if attempt >= policy.retries || !is_retryable(&err) {
+            return Err(err);
+        }
+
+        let delay = policy.delay(attempt);
+        attempt += 1;
+        warn!(
+            "{}",
+            fl!(
+                "http-retrying",
+                error = err.to_string(),
+                attempt = attempt,
+                retries = policy.retries,
+                delay = delay.as_secs_f32()
+            )
+        );
+        metrics::counter!("emtt_http_retries_total").increment(1);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Sends a request built by `make_request`, retrying with exponential backoff
+pub async fn send_with_retry<F>(
+    policy: &RetryPolicy,
+    make_request: F,
+) -> Result<Response, SendError>
+where
+    F: Fn() -> RequestBuilder,
+{
+    let make_request = &make_request;
+    retry(
+        policy,
+        move || async move {
+            match make_request().timeout(policy.timeout).send().await {
+                Ok(response) if response.status().is_success() => Ok(response),
+                Ok(response) => {
+                    let status = response.status();
+                    let body = response.text().await.unwrap_or_default();
+                    Err(SendError::Status(
+                        status,
+                        truncate_body(body.trim().to_string()),
+                    ))
+                }
+                Err(e) => Err(SendError::Request(e)),
+            }
+        },
+        SendError::is_retryable,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: u16) -> SendError {
+        SendError::Status(StatusCode::from_u16(code).unwrap(), String::new())
+    }
+
+    #[test]
+    fn server_errors_and_rate_limits_are_retried() {
+        for code in [500, 502, 503, 504, 429] {
+            assert!(status(code).is_retryable(), "{code}");
+        }
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        for code in [400, 401, 403, 404, 405, 413, 422] {
+            assert!(!status(code).is_retryable(), "{code}");
+        }
+    }
+
+    #[tokio::test]
+    async fn transport_errors_are_retried() {
+        // Nothing listens on the discard port, so the connection is refused
+        let err = reqwest::Client::new()
+            .get("http://127.0.0.1:9/")
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(SendError::Request(err).is_retryable());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let policy = RetryPolicy {
+            retries: 100,
+            timeout: Duration::from_secs(10),
+            backoff: Duration::from_secs(1),
+        };
+        assert_eq!(policy.delay(0), Duration::from_secs(1));
+        assert_eq!(policy.delay(3), Duration::from_secs(8));
+        assert_eq!(policy.delay(9), MAX_BACKOFF);
+        assert_eq!(policy.delay(31), MAX_BACKOFF);
+        assert_eq!(policy.delay(100), MAX_BACKOFF);
+
+        let huge = RetryPolicy {
+            backoff: Duration::from_secs(u64::MAX / 2),
+            ..policy
+        };
+        assert_eq!(huge.delay(5), MAX_BACKOFF);
+    }
+}
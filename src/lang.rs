@@ -27,10 +27,10 @@ pub static LANGUAGE_LOADER: Lazy<FluentLanguageLoader> = Lazy::new(|| {
 #[macro_export]
 macro_rules! fl {
     ($message_id:literal) => {{
-        i18n_embed_fl::fl!(crate::lang::LANGUAGE_LOADER, $message_id)
+        i18n_embed_fl::fl!($crate::lang::LANGUAGE_LOADER, $message_id)
     }};
     ($message_id:literal, $($args:expr),*) => {{
-        i18n_embed_fl::fl!(crate::lang::LANGUAGE_LOADER, $message_id, $($args), *)
+        i18n_embed_fl::fl!($crate::lang::LANGUAGE_LOADER, $message_id, $($args), *)
     }};
 }
 
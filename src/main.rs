@@ -2,16 +2,18 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use clap_i18n_richformatter::{
     ClapI18nRichFormatter, clap_i18n, init_clap_rich_formatter_localizer,
 };
 use env_logger::Env;
 use log::LevelFilter;
-use minijinja::{AutoEscape, Environment, Output, State, Value, context, value::ValueKind};
+use minijinja::{AutoEscape, Environment, Output, State, Value, value::ValueKind};
 use reqwest::{ClientBuilder, Proxy};
 use serde::Serialize;
 use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::LazyLock;
 use std::time::Duration;
@@ -19,12 +21,32 @@ use teloxide::types::ParseMode;
 use teloxide::utils::{html, markdown};
 use tokio_graceful_shutdown::{SubsystemBuilder, Toplevel};
 
+mod alerts;
+mod archive;
+mod bot;
+mod config;
+mod database;
+mod discord;
+mod email;
+mod events;
+mod exec;
+mod gateways;
+mod homeassistant;
+mod http;
 mod lang;
+mod matrix;
+mod monitoring;
+mod mqtt;
+mod nodes;
+mod notifications;
+mod payload;
+mod push;
+mod roster;
 mod syslog;
 mod telegram;
 mod webhook;
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct MessageData {
     from: String,
     via: String,
@@ -32,6 +54,34 @@ pub struct MessageData {
     snr: Option<f32>,
     rssi: Option<i32>,
     hops_away: Option<i32>,
+    #[serde(skip)]
+    id: u32,
+    #[serde(skip)]
+    from_id: u32,
+    #[serde(skip)]
+    to: u32,
+    #[serde(skip)]
+    reply_id: Option<u32>,
+    #[serde(skip)]
+    is_reaction: bool,
+    #[serde(skip)]
+    channel: u32,
+    #[serde(skip)]
+    shortname: Option<String>,
+    #[serde(skip)]
+    longname: Option<String>,
+    #[serde(skip)]
+    received_at: u64,
+    #[serde(skip)]
+    rx_time: Option<u64>,
+    #[serde(skip)]
+    is_name_conflict: bool,
+}
+
+impl MessageData {
+    fn is_direct(&self) -> bool {
+        self.to != syslog::BROADCAST_ADDR
+    }
 }
 
 // Log level (global CLI arg + localized --help)
@@ -79,6 +129,22 @@ enum Commands {
         #[arg(help = fl!("arg-webhook-url"))]
         webhook_url: Option<String>,
 
+        #[arg(long, env = "WEBHOOK_SECRET")]
+        #[arg(help = fl!("arg-webhook-secret"))]
+        webhook_secret: Option<String>,
+
+        #[arg(long, env = "WEBHOOK_FORMAT", default_value = "v1")]
+        #[arg(help = fl!("arg-webhook-format"))]
+        webhook_format: payload::PayloadFormat,
+
+        #[arg(long, env = "WEBHOOK_RETRIES", default_value = "3")]
+        #[arg(help = fl!("arg-webhook-retries"))]
+        webhook_retries: u32,
+
+        #[arg(long, env = "WEBHOOK_TIMEOUT", default_value = "10")]
+        #[arg(help = fl!("arg-webhook-timeout"))]
+        webhook_timeout: u64,
+
         #[arg(
             long,
             env = "MESH_DM",
@@ -121,10 +187,151 @@ enum Commands {
         #[arg(long, env = "TELEGRAM_API_SERVER")]
         #[arg(help = fl!("arg-api-server"))]
         api_server: Option<String>,
+
+        #[arg(
+            long,
+            env = "TELEGRAM_EDIT_DUPLICATES",
+            value_parser = clap::value_parser!(bool),
+            default_value_t = false,
+            num_args = 0..=1,
+            default_missing_value = "true",
+        )]
+        #[arg(help = fl!("arg-edit-duplicates"))]
+        edit_duplicates: bool,
+
+        #[arg(
+            long,
+            env = "TELEGRAM_REPLY_THREADING",
+            value_parser = clap::value_parser!(bool),
+            default_value_t = true,
+            num_args = 0..=1,
+            default_missing_value = "true",
+        )]
+        #[arg(help = fl!("arg-reply-threading"))]
+        reply_threading: bool,
+
+        #[arg(
+            long,
+            env = "TELEGRAM_INLINE_KEYBOARD",
+            value_parser = clap::value_parser!(bool),
+            default_value_t = false,
+            num_args = 0..=1,
+            default_missing_value = "true",
+        )]
+        #[arg(help = fl!("arg-inline-keyboard"))]
+        inline_keyboard: bool,
+
+        #[arg(
+            long,
+            env = "TELEGRAM_BOT_COMMANDS",
+            value_parser = clap::value_parser!(bool),
+            default_value_t = false,
+            num_args = 0..=1,
+            default_missing_value = "true",
+        )]
+        #[arg(help = fl!("arg-bot-commands"))]
+        bot_commands: bool,
+
+        #[arg(long = "config", env = "CONFIG_FILE")]
+        #[arg(help = fl!("arg-config"))]
+        config_file: Option<PathBuf>,
+
+        #[arg(long, env = "ARCHIVE_DB")]
+        #[arg(help = fl!("arg-archive-db"))]
+        archive_db: Option<PathBuf>,
+
+        #[arg(long, env = "HTTP_LISTEN")]
+        #[arg(help = fl!("arg-http-listen"))]
+        http_listen: Option<SocketAddr>,
+    },
+
+    /// Query the SQLite message archive
+    #[command(about = fl!("command-archive"))]
+    Archive {
+        #[command(subcommand)]
+        command: ArchiveCommand,
     },
 }
+
+#[derive(Subcommand)]
+enum ArchiveCommand {
+    #[command(about = fl!("command-archive-search"))]
+    Search {
+        #[command(flatten)]
+        query: ArchiveQuery,
+
+        #[arg(long, default_value = "50")]
+        #[arg(help = fl!("arg-limit"))]
+        limit: u32,
+    },
+
+    #[command(about = fl!("command-archive-export"))]
+    Export {
+        #[command(flatten)]
+        query: ArchiveQuery,
+
+        #[arg(long, default_value = "csv")]
+        #[arg(help = fl!("arg-export-format"))]
+        format: database::ExportFormat,
+
+        #[arg(long, short = 'o')]
+        #[arg(help = fl!("arg-output"))]
+        output: Option<PathBuf>,
+    },
+}
+
+// Database and filters shared by the archive subcommands
+#[derive(Args)]
+struct ArchiveQuery {
+    #[arg(long, env = "ARCHIVE_DB")]
+    #[arg(help = fl!("arg-db"))]
+    db: PathBuf,
+
+    #[arg(long)]
+    #[arg(help = fl!("arg-from"))]
+    from: Option<String>,
+
+    #[arg(long)]
+    #[arg(help = fl!("arg-text"))]
+    text: Option<String>,
+
+    #[arg(long)]
+    #[arg(help = fl!("arg-via"))]
+    via: Option<String>,
+
+    #[arg(long)]
+    #[arg(help = fl!("arg-filter-channel"))]
+    channel: Option<u32>,
+
+    #[arg(long)]
+    #[arg(help = fl!("arg-since"))]
+    since: Option<String>,
+
+    #[arg(long)]
+    #[arg(help = fl!("arg-until"))]
+    until: Option<String>,
+}
+
+impl ArchiveQuery {
+    fn filter(self) -> (PathBuf, database::Filter) {
+        (
+            self.db,
+            database::Filter {
+                from: self.from,
+                text: self.text,
+                via: self.via,
+                channel: self.channel,
+                since: self.since,
+                until: self.until,
+            },
+        )
+    }
+}
 // --- End Commands definition ---
 
+// Delay before the first webhook retry, doubled on every next attempt
+const WEBHOOK_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
 pub static HELP_HEADING: LazyLock<String> = LazyLock::new(|| fl!("command-syslog"));
 pub static ARG_HELP_HEADING: LazyLock<String> = LazyLock::new(|| fl!("arg-bot-token"));
 pub static HELP_TEMPLATE: LazyLock<String> = LazyLock::new(|| {
@@ -175,11 +382,21 @@ enum ParseModeOpt {
     Markdown,
 }
 
+impl ParseModeOpt {
+    fn telegram(self) -> Option<ParseMode> {
+        match self {
+            ParseModeOpt::None => None,
+            ParseModeOpt::Html => Some(ParseMode::Html),
+            ParseModeOpt::Markdown => Some(ParseMode::MarkdownV2),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Config {
     bot_token: Option<String>,
     chat_ids: Vec<i64>,
-    webhook_url: Option<String>,
+    webhooks: Vec<webhook::Webhook>,
     dm: bool,
     channel: Option<u32>,
     template: String,
@@ -188,6 +405,22 @@ struct Config {
     syslog_port: u16,
     proxy_url: Option<String>,
     api_server: Option<String>,
+    edit_duplicates: bool,
+    reply_threading: bool,
+    inline_keyboard: bool,
+    bot_commands: bool,
+    notifications: notifications::NotificationRules,
+    alerts: alerts::AlertRules,
+    discord: discord::DiscordSink,
+    matrix: Option<matrix::MatrixSink>,
+    mqtt: Option<mqtt::MqttSink>,
+    push: push::PushSink,
+    email: Option<email::EmailSink>,
+    exec: exec::ExecSink,
+    archive: Option<archive::ArchiveSink>,
+    message_db: Option<database::MessageDb>,
+    gateways: gateways::GatewayMonitor,
+    roster: roster::NodeRoster,
 }
 
 fn unescape_template(s: String) -> String {
@@ -244,6 +477,9 @@ fn telegram_escape_formatter(
                 "telegram_markdown" => {
                     write!(out, "{}", markdown::escape(s))?;
                 }
+                "discord_markdown" => {
+                    write!(out, "{}", discord::escape(s))?;
+                }
                 _ => {
                     write!(out, "{}", s)?;
                 }
@@ -349,6 +585,10 @@ async fn main() {
             bot_token,
             chat_ids,
             webhook_url,
+            webhook_secret,
+            webhook_format,
+            webhook_retries,
+            webhook_timeout,
             dm,
             channel,
             template,
@@ -357,12 +597,199 @@ async fn main() {
             syslog_port,
             proxy_url,
             api_server,
+            edit_duplicates,
+            reply_threading,
+            inline_keyboard,
+            bot_commands,
+            config_file,
+            archive_db,
+            http_listen,
         } => {
             let template = unescape_template(template);
+
+            let metrics_handle = match http_listen {
+                Some(_) => match monitoring::install_recorder() {
+                    Ok(h) => Some(h),
+                    Err(e) => {
+                        log::error!("{}", fl!("app-exit-error", error = format!("{:#}", e)));
+                        shutdown(1);
+                    }
+                },
+                None => None,
+            };
+
+            let file_config = match &config_file {
+                Some(path) => match config::load(path) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        log::error!(
+                            "{}",
+                            fl!(
+                                "config-load-error",
+                                path = path.display().to_string(),
+                                error = format!("{:#}", e)
+                            )
+                        );
+                        shutdown(2);
+                    }
+                },
+                None => config::ConfigFile::default(),
+            };
+
+            let notifications =
+                match notifications::NotificationRules::compile(&file_config.notifications) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        log::error!(
+                            "{}",
+                            fl!("invalid-notification-rules", error = format!("{:#}", e))
+                        );
+                        shutdown(2);
+                    }
+                };
+
+            let webhook_defaults = webhook::WebhookDefaults {
+                secret: webhook_secret,
+                policy: http::RetryPolicy {
+                    retries: webhook_retries,
+                    timeout: Duration::from_secs(webhook_timeout),
+                    backoff: WEBHOOK_RETRY_BACKOFF,
+                },
+                format: webhook_format,
+            };
+
+            let alerts = match alerts::AlertRules::compile(&file_config.alerts, &webhook_defaults) {
+                Ok(r) => r,
+                Err(e) => {
+                    log::error!("{}", fl!("invalid-alert-rules", error = format!("{:#}", e)));
+                    shutdown(2);
+                }
+            };
+
+            let mut webhooks: Vec<webhook::Webhook> = webhook_url
+                .map(|url| webhook::Webhook::new(url, &webhook_defaults))
+                .into_iter()
+                .collect();
+            for webhook_config in &file_config.webhooks {
+                match webhook::Webhook::compile(webhook_config, &webhook_defaults) {
+                    Ok(w) => webhooks.push(w),
+                    Err(e) => {
+                        log::error!("{}", fl!("invalid-webhook", error = format!("{:#}", e)));
+                        shutdown(2);
+                    }
+                }
+            }
+
+            let discord =
+                match discord::DiscordSink::compile(&file_config.discord, webhook_defaults.policy) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        log::error!("{}", fl!("invalid-discord-route", error = format!("{:#}", e)));
+                        shutdown(2);
+                    }
+                };
+
+            let matrix = match &file_config.matrix {
+                Some(c) => match matrix::MatrixSink::compile(c, webhook_defaults.policy) {
+                    Ok(m) => Some(m),
+                    Err(e) => {
+                        log::error!("{}", fl!("invalid-matrix-config", error = format!("{:#}", e)));
+                        shutdown(2);
+                    }
+                },
+                None => None,
+            };
+
+            let (mqtt, mqtt_eventloop) = match &file_config.mqtt {
+                Some(c) => match mqtt::MqttSink::new(c) {
+                    Ok((sink, eventloop)) => (Some(sink), Some(eventloop)),
+                    Err(e) => {
+                        log::error!("{}", fl!("invalid-mqtt-config", error = format!("{:#}", e)));
+                        shutdown(2);
+                    }
+                },
+                None => (None, None),
+            };
+
+            let push = match push::PushSink::compile(
+                &file_config.ntfy,
+                &file_config.gotify,
+                webhook_defaults.policy,
+            ) {
+                Ok(p) => p,
+                Err(e) => {
+                    log::error!("{}", fl!("invalid-push-route", error = format!("{:#}", e)));
+                    shutdown(2);
+                }
+            };
+
+            let (email, email_worker) = match &file_config.email {
+                Some(c) => match email::EmailSink::new(c, webhook_defaults.policy) {
+                    Ok((sink, worker)) => (Some(sink), Some(worker)),
+                    Err(e) => {
+                        log::error!("{}", fl!("invalid-email-config", error = format!("{:#}", e)));
+                        shutdown(2);
+                    }
+                },
+                None => (None, None),
+            };
+
+            let exec = match exec::ExecSink::compile(&file_config.exec) {
+                Ok(e) => e,
+                Err(e) => {
+                    log::error!("{}", fl!("invalid-exec-hook", error = format!("{:#}", e)));
+                    shutdown(2);
+                }
+            };
+
+            let (archive, archive_writer) = match &file_config.archive {
+                Some(c) => match archive::ArchiveSink::new(c) {
+                    Ok((sink, writer)) => (Some(sink), Some(writer)),
+                    Err(e) => {
+                        log::error!("{}", fl!("invalid-archive-config", error = format!("{:#}", e)));
+                        shutdown(2);
+                    }
+                },
+                None => (None, None),
+            };
+
+            let (message_db, message_db_writer) = match &archive_db {
+                Some(path) => match database::MessageDb::new(path) {
+                    Ok((db, writer)) => (Some(db), Some(writer)),
+                    Err(e) => {
+                        log::error!("{}", fl!("invalid-database", error = format!("{:#}", e)));
+                        shutdown(2);
+                    }
+                },
+                None => (None, None),
+            };
+
+            let gateways = gateways::GatewayMonitor::default();
+            let gateway_watcher = match &file_config.gateways {
+                Some(c) => match gateways.watcher(c, &chat_ids, parse_mode) {
+                    Ok(watcher) => Some(watcher),
+                    Err(e) => {
+                        log::error!("{}", fl!("invalid-gateways-config", error = format!("{:#}", e)));
+                        shutdown(2);
+                    }
+                },
+                None => None,
+            };
+
+            // Name conflicts are flagged even without a [nodes] section
+            let nodes_config = file_config.nodes.unwrap_or_default();
+            let (roster, roster_writer) = match roster::NodeRoster::new(&nodes_config, &chat_ids, parse_mode) {
+                Ok(r) => r,
+                Err(e) => {
+                    log::error!("{}", fl!("invalid-nodes-config", error = format!("{:#}", e)));
+                    shutdown(2);
+                }
+            };
+
             let config = Config {
                 bot_token,
                 chat_ids,
-                webhook_url,
+                webhooks,
                 dm,
                 channel,
                 template,
@@ -371,18 +798,69 @@ async fn main() {
                 syslog_port,
                 proxy_url,
                 api_server,
+                edit_duplicates,
+                reply_threading,
+                inline_keyboard,
+                bot_commands,
+                notifications,
+                alerts,
+                discord,
+                matrix,
+                mqtt,
+                push,
+                email,
+                exec,
+                archive,
+                message_db,
+                gateways,
+                roster,
             };
 
             let use_telegram = config.bot_token.is_some() && !config.chat_ids.is_empty();
-            let use_webhook = config.webhook_url.is_some();
-
-            if !use_telegram && !use_webhook {
+            let use_webhook = !config.webhooks.is_empty();
+            let use_discord = config.discord.route_count() > 0;
+            let use_matrix = config.matrix.is_some();
+            let use_mqtt = config.mqtt.is_some();
+            let use_push = config.push.route_count() > 0;
+            let use_email = config.email.is_some();
+            let use_exec = config.exec.hook_count() > 0;
+            let use_archive = config.archive.is_some() || config.message_db.is_some();
+
+            if !use_telegram
+                && !use_webhook
+                && !use_discord
+                && !use_matrix
+                && !use_mqtt
+                && !use_push
+                && !use_email
+                && !use_exec
+                && !use_archive
+            {
                 log::error!("{}", fl!("no-output-configured"));
                 shutdown(2);
             }
 
+            if config.alerts.needs_bot() && config.bot_token.is_none() {
+                log::error!("{}", fl!("alerts-require-bot-token"));
+                shutdown(2);
+            }
+
+            if gateway_watcher.is_some() && config.bot_token.is_none() {
+                log::error!("{}", fl!("gateways-require-bot-token"));
+                shutdown(2);
+            }
+
+            if config.roster.needs_bot() && config.bot_token.is_none() {
+                log::error!("{}", fl!("nodes-require-bot-token"));
+                shutdown(2);
+            }
+
             log::info!("{}", fl!("starting-syslog-mode"));
 
+            if let Some(path) = &config_file {
+                log::info!("{}", fl!("config-loaded", path = path.display().to_string()));
+            }
+
             if use_telegram {
                 let ids = config
                     .chat_ids
@@ -404,6 +882,48 @@ async fn main() {
                 } else {
                     log::info!("{}", fl!("bot-api-server-official"));
                 }
+
+                log::info!(
+                    "{}",
+                    fl!(
+                        "edit-duplicates",
+                        enabled = lang::localize_bool(config.edit_duplicates)
+                    )
+                );
+
+                log::info!(
+                    "{}",
+                    fl!(
+                        "reply-threading",
+                        enabled = lang::localize_bool(config.reply_threading)
+                    )
+                );
+
+                log::info!(
+                    "{}",
+                    fl!(
+                        "inline-keyboard",
+                        enabled = lang::localize_bool(config.inline_keyboard)
+                    )
+                );
+
+                log::info!(
+                    "{}",
+                    fl!(
+                        "bot-commands",
+                        enabled = lang::localize_bool(config.bot_commands)
+                    )
+                );
+
+                if config.notifications.rule_count() > 0 {
+                    log::info!(
+                        "{}",
+                        fl!(
+                            "silent-rules-enabled",
+                            count = config.notifications.rule_count()
+                        )
+                    );
+                }
             }
 
             log::info!("{}", fl!("forward-dm", dm = lang::localize_bool(config.dm)));
@@ -414,18 +934,105 @@ async fn main() {
                 log::info!("{}", fl!("channel-disabled"));
             }
 
+            if config.alerts.rule_count() > 0 {
+                log::info!(
+                    "{}",
+                    fl!("alert-rules-enabled", count = config.alerts.rule_count())
+                );
+            }
+
+            if let Some(path) = config.roster.path() {
+                log::info!(
+                    "{}",
+                    fl!(
+                        "roster-loaded",
+                        path = path.display().to_string(),
+                        count = config.roster.node_count()
+                    )
+                );
+            }
+
+            if let Some(watcher) = &gateway_watcher {
+                log::info!(
+                    "{}",
+                    fl!(
+                        "gateway-monitor-enabled",
+                        timeout = watcher.timeout().as_secs()
+                    )
+                );
+            }
+
             if use_webhook {
+                for webhook in &config.webhooks {
+                    log::info!("{}", fl!("webhook-enabled", url = webhook.url.clone()));
+                    log::info!(
+                        "{}",
+                        fl!("webhook-format", format = webhook.format.name())
+                    );
+                    log::info!(
+                        "{}",
+                        fl!(
+                            "webhook-signing",
+                            enabled = lang::localize_bool(webhook.secret.is_some())
+                        )
+                    );
+                }
                 log::info!(
                     "{}",
                     fl!(
-                        "webhook-enabled",
-                        url = config.webhook_url.as_ref().unwrap()
+                        "webhook-retry-policy",
+                        retries = webhook_defaults.policy.retries,
+                        timeout = webhook_defaults.policy.timeout.as_secs()
                     )
                 );
             } else {
                 log::info!("{}", fl!("webhook-disabled"));
             }
 
+            if use_discord {
+                log::info!(
+                    "{}",
+                    fl!("discord-enabled", count = config.discord.route_count())
+                );
+            }
+
+            if let Some(matrix) = &config.matrix {
+                log::info!("{}", fl!("matrix-enabled", count = matrix.room_count()));
+            }
+
+            if let Some(mqtt) = &config.mqtt {
+                log::info!("{}", fl!("mqtt-enabled", broker = mqtt.broker()));
+                if mqtt.homeassistant_enabled() {
+                    log::info!("{}", fl!("homeassistant-enabled"));
+                }
+            }
+
+            if use_push {
+                log::info!("{}", fl!("push-enabled", count = config.push.route_count()));
+            }
+
+            if let Some(email) = &config.email {
+                log::info!("{}", fl!("email-enabled", count = email.recipient_count()));
+            }
+
+            if use_exec {
+                log::info!("{}", fl!("exec-enabled", count = config.exec.hook_count()));
+            }
+
+            if let Some(archive) = &config.archive {
+                log::info!(
+                    "{}",
+                    fl!("archive-enabled", path = archive.path().display().to_string())
+                );
+            }
+
+            if let Some(db) = &config.message_db {
+                log::info!(
+                    "{}",
+                    fl!("database-enabled", path = db.path().display().to_string())
+                );
+            }
+
             let mut client_builder = ClientBuilder::new();
 
             if let Some(proxy_url) = &config.proxy_url {
@@ -455,7 +1062,11 @@ async fn main() {
                 }
             };
 
-            let bot = if use_telegram {
+            let bot = if use_telegram
+                || config.alerts.needs_bot()
+                || gateway_watcher.is_some()
+                || config.roster.needs_bot()
+            {
                 let token = config.bot_token.clone().unwrap();
                 let bot_base = telegram::init_bot(token, http_client.clone());
 
@@ -483,86 +1094,150 @@ async fn main() {
                 None
             };
 
+            let telegram_sink = if use_telegram {
+                Some(telegram::TelegramSink::new(bot.clone().unwrap(), &config))
+            } else {
+                None
+            };
+
+            let health = monitoring::Health::new(use_telegram);
+
             let sender = {
+                let health = health.clone();
                 let bot = bot.clone();
-                let chat_ids = config.chat_ids.clone();
+                let telegram_sink = telegram_sink.clone();
                 let template = config.template.clone();
                 let parse_mode_opt = config.parse_mode;
-                let webhook_url = config.webhook_url.clone();
-                let use_telegram = use_telegram;
-                let use_webhook = use_webhook;
+                let webhooks = config.webhooks.clone();
+                let discord = config.discord.clone();
+                let matrix = config.matrix.clone();
+                let mqtt = config.mqtt.clone();
+                let push = config.push.clone();
+                let email = config.email.clone();
+                let exec = config.exec.clone();
+                let archive = config.archive.clone();
+                let message_db = config.message_db.clone();
+                let gateways = config.gateways.clone();
+                let roster = config.roster.clone();
+                let alerts = config.alerts.clone();
                 let http_client = http_client.clone();
 
-                move |data: MessageData| {
+                move |event: events::Event| {
+                    let health = health.clone();
                     let bot = bot.clone();
-                    let chat_ids = chat_ids.clone();
+                    let telegram_sink = telegram_sink.clone();
                     let template = template.clone();
-                    let parse_mode_opt = parse_mode_opt;
-                    let webhook_url = webhook_url.clone();
+                    let webhooks = webhooks.clone();
+                    let discord = discord.clone();
+                    let matrix = matrix.clone();
+                    let mqtt = mqtt.clone();
+                    let push = push.clone();
+                    let email = email.clone();
+                    let exec = exec.clone();
+                    let archive = archive.clone();
+                    let message_db = message_db.clone();
+                    let gateways = gateways.clone();
+                    let roster = roster.clone();
+                    let alerts = alerts.clone();
                     let http_client = http_client.clone();
 
                     Box::pin(async move {
-                        if use_telegram {
-                            let env = create_template_env(parse_mode_opt);
-
-                            let ctx = context! {
-                                from => data.from,
-                                via => data.via,
-                                text => data.text,
-                                snr => data.snr,
-                                rssi => data.rssi,
-                                hops_away => data.hops_away,
-                            };
-
-                            let rendered_result = env.render_str(&template, ctx);
-
-                            if let Ok(rendered) = rendered_result {
-                                let parse_mode = match parse_mode_opt {
-                                    ParseModeOpt::None => None,
-                                    ParseModeOpt::Html => Some(ParseMode::Html),
-                                    ParseModeOpt::Markdown => Some(ParseMode::MarkdownV2),
-                                };
-
-                                for chat_id in &chat_ids {
-                                    match telegram::send_message(
-                                        bot.as_ref().unwrap(),
-                                        *chat_id,
-                                        &rendered,
-                                        parse_mode,
-                                    )
-                                    .await
-                                    {
-                                        Err(err) => {
-                                            log::warn!(
-                                                "{}\n{}",
-                                                fl!("failed-to-send", error = err.to_string()),
-                                                fl!("message-content", content = rendered.clone())
-                                            );
-                                        }
-                                        Ok(_) => {
-                                            log::debug!(
-                                                "{}",
-                                                fl!(
-                                                    "forwarded-to-telegram",
-                                                    from = data.from.clone(),
-                                                    message = rendered.clone()
-                                                )
-                                            );
-                                        }
-                                    }
-                                }
-                            } else if let Err(e) = rendered_result {
-                                log::warn!("{}", fl!("failed-to-render", error = e.to_string()));
-                            }
+                        if let Some(archive) = &archive {
+                            archive.record(&event);
                         }
 
-                        if use_webhook {
-                            webhook::send_message(
+                        if let Some(message_db) = &message_db {
+                            message_db.record(&event);
+                        }
+
+                        if let events::Event::Telemetry(telemetry) = &event {
+                            gateways.record_telemetry(telemetry);
+                        }
+
+                        if let events::Event::NodeInfo(info) = &event {
+                            roster.observe(info, bot.as_ref(), &health).await;
+                        }
+
+                        if let Some(mqtt) = &mqtt {
+                            mqtt.publish(&event);
+                        }
+
+                        if let events::Event::Text(data) = &event
+                            && let Some(telegram_sink) = &telegram_sink
+                        {
+                            let _sending = health.outbox_entry();
+                            telegram_sink.deliver(data).await;
+                        }
+
+                        // Retries shouldn't hold up the syslog server
+                        for webhook in webhooks.into_iter().filter(|w| w.accepts(&event)) {
+                            let http_client = http_client.clone();
+                            let event = event.clone();
+                            let sending = health.outbox_entry();
+                            tokio::spawn(async move {
+                                let _sending = sending;
+                                webhook.send(&http_client, &event).await;
+                            });
+                        }
+
+                        if let events::Event::Text(data) = &event
+                            && use_discord
+                        {
+                            let http_client = http_client.clone();
+                            let data = data.clone();
+                            let sending = health.outbox_entry();
+                            tokio::spawn(async move {
+                                let _sending = sending;
+                                discord.deliver(&http_client, &data).await;
+                            });
+                        }
+
+                        if let events::Event::Text(data) = &event
+                            && let Some(matrix) = matrix
+                        {
+                            let http_client = http_client.clone();
+                            let data = data.clone();
+                            let sending = health.outbox_entry();
+                            tokio::spawn(async move {
+                                let _sending = sending;
+                                matrix.deliver(&http_client, &data).await;
+                            });
+                        }
+
+                        if let events::Event::Text(data) = &event
+                            && use_push
+                        {
+                            let http_client = http_client.clone();
+                            let data = data.clone();
+                            let alert = alerts.matching(&data.text).map(str::to_string);
+                            let sending = health.outbox_entry();
+                            tokio::spawn(async move {
+                                let _sending = sending;
+                                push.deliver(&http_client, &data, alert.as_deref()).await;
+                            });
+                        }
+
+                        if let events::Event::Text(data) = &event
+                            && let Some(email) = &email
+                        {
+                            email.deliver(data);
+                        }
+
+                        if let events::Event::Text(data) = &event
+                            && use_exec
+                        {
+                            exec.deliver(data);
+                        }
+
+                        if let events::Event::Text(data) = &event {
+                            alerts.process(
+                                data,
+                                bot.as_ref(),
                                 &http_client,
-                                webhook_url.as_ref().unwrap(),
-                                &data,
-                            )
-                            .await;
+                                &template,
+                                parse_mode_opt,
+                                &health,
+                            );
                         }
                     }) as Pin<Box<dyn Future<Output = ()> + Send>>
                 }
@@ -570,9 +1245,85 @@ async fn main() {
 
             log::info!("{}", fl!("syslog-server"));
 
+            let known_nodes = config.roster.nodes();
+
+            // The bot only needs to receive updates to answer inline keyboard buttons and commands
+            let bot_updates = if use_telegram && (config.inline_keyboard || config.bot_commands) {
+                bot.clone()
+            } else {
+                None
+            };
+
+            // Readiness waits for Telegram, the bot checks it itself when it receives updates
+            let telegram_check = if use_telegram && http_listen.is_some() && bot_updates.is_none() {
+                bot.clone()
+            } else {
+                None
+            };
+
+            let mqtt_broker = config.mqtt.as_ref().map(|m| m.broker().to_string());
+            let gateways = config.gateways.clone();
+            let command_chats = config.bot_commands.then(|| config.chat_ids.clone());
+
             let result = Toplevel::new(move |s| async move {
+                if let (Some(eventloop), Some(broker)) = (mqtt_eventloop, mqtt_broker) {
+                    s.start(SubsystemBuilder::new("mqtt", move |subsys| {
+                        mqtt::run_eventloop(subsys, eventloop, broker)
+                    }));
+                }
+
+                if let Some(writer) = message_db_writer {
+                    s.start(SubsystemBuilder::new("database", move |subsys| {
+                        writer.run(subsys)
+                    }));
+                }
+
+                if let Some(writer) = roster_writer {
+                    s.start(SubsystemBuilder::new("roster", move |subsys| {
+                        writer.run(subsys)
+                    }));
+                }
+
+                if let Some(writer) = archive_writer {
+                    s.start(SubsystemBuilder::new("archive", move |subsys| {
+                        writer.run(subsys)
+                    }));
+                }
+
+                if let (Some(watcher), Some(bot)) = (gateway_watcher, bot.clone()) {
+                    s.start(SubsystemBuilder::new("gateway-monitor", move |subsys| {
+                        watcher.run(subsys, bot)
+                    }));
+                }
+
+                if let Some(worker) = email_worker {
+                    s.start(SubsystemBuilder::new("email", move |subsys| {
+                        worker.run(subsys)
+                    }));
+                }
+
+                if let Some(bot) = bot_updates {
+                    let known_nodes = known_nodes.clone();
+                    let health = health.clone();
+                    s.start(SubsystemBuilder::new("telegram-bot", move |subsys| {
+                        bot::run_bot(subsys, bot, known_nodes, gateways, health, command_chats)
+                    }));
+                } else if let Some(bot) = telegram_check {
+                    let health = health.clone();
+                    s.start(SubsystemBuilder::new("telegram-check", move |subsys| {
+                        bot::check_telegram(subsys, bot, health)
+                    }));
+                }
+
+                if let (Some(addr), Some(handle)) = (http_listen, metrics_handle) {
+                    let health = health.clone();
+                    s.start(SubsystemBuilder::new("http-server", move |subsys| {
+                        monitoring::run_server(subsys, addr, handle, health)
+                    }));
+                }
+
                 s.start(SubsystemBuilder::new("syslog-server", move |subsys| {
-                    syslog::run_server(subsys, config, sender)
+                    syslog::run_server(subsys, config, known_nodes, health, sender)
                 }));
             })
             .catch_signals()
@@ -587,5 +1338,25 @@ async fn main() {
                 }
             }
         }
+        Commands::Archive { command } => {
+            let result = match command {
+                ArchiveCommand::Search { query, limit } => {
+                    let (db, filter) = query.filter();
+                    database::search(&db, &filter, limit)
+                }
+                ArchiveCommand::Export {
+                    query,
+                    format,
+                    output,
+                } => {
+                    let (db, filter) = query.filter();
+                    database::export(&db, &filter, format, output.as_deref())
+                }
+            };
+            if let Err(e) = result {
+                log::error!("{}", fl!("archive-query-error", error = format!("{:#}", e)));
+                shutdown(1);
+            }
+        }
     }
 }
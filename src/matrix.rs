@@ -0,0 +1,220 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result, bail};
+use log::{debug, warn};
+use minijinja::{Environment, context};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::http::{RetryPolicy, send_with_retry};
+use crate::notifications::{MessageKind, route_matches};
+use crate::{MessageData, ParseModeOpt, fl};
+
+const DEFAULT_TEMPLATE: &str = "{{ from }} (via {{ via }}):\n{{ text }}";
+const DEFAULT_HTML_TEMPLATE: &str =
+    "<b>{{ from }}</b> (via <i>{{ via }}</i>)<blockquote>{{ text }}</blockquote>";
+
+// The `[matrix]` section of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixConfig {
+    homeserver_url: String,
+    access_token: String,
+    room_ids: Vec<String>,
+    template: Option<String>,
+    html_template: Option<String>,
+    // m.notice is the message type for bots, clients may notify about it less eagerly
+    #[serde(default)]
+    notice: bool,
+    #[serde(default)]
+    messages: MessageKind,
+    #[serde(default)]
+    channels: Vec<u32>,
+}
+
+#[derive(Clone)]
+pub struct MatrixSink {
+    homeserver_url: Url,
+    access_token: String,
+    room_ids: Vec<String>,
+    template: String,
+    html_template: String,
+    msgtype: &'static str,
+    messages: MessageKind,
+    channels: Vec<u32>,
+    policy: RetryPolicy,
+    // Transaction IDs make retried requests idempotent, so they must be unique per event
+    txn_prefix: String,
+    txn_counter: Arc<AtomicU64>,
+}
+
+fn render(
+    env: &Environment,
+    template: &str,
+    data: &MessageData,
+) -> Result<String, minijinja::Error> {
+    let ctx = context! {
+        from => data.from.clone(),
+        is_name_conflict => data.is_name_conflict,
+        via => data.via.clone(),
+        text => data.text.clone(),
+        snr => data.snr,
+        rssi => data.rssi,
+        hops_away => data.hops_away,
+        channel => data.channel,
+    };
+    env.render_str(template, ctx)
+}
+
+impl MatrixSink {
+    pub fn compile(config: &MatrixConfig, policy: RetryPolicy) -> Result<Self> {
+        let homeserver_url = Url::parse(&config.homeserver_url)
+            .with_context(|| format!("Invalid homeserver URL {}", config.homeserver_url))?;
+        if homeserver_url.cannot_be_a_base() {
+            bail!("Invalid homeserver URL {}", config.homeserver_url);
+        }
+        if config.room_ids.is_empty() {
+            bail!("No Matrix rooms configured");
+        }
+
+        let template = config
+            .template
+            .clone()
+            .map(crate::unescape_template)
+            .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+        let html_template = config
+            .html_template
+            .clone()
+            .map(crate::unescape_template)
+            .unwrap_or_else(|| DEFAULT_HTML_TEMPLATE.to_string());
+
+        crate::create_template_env(ParseModeOpt::None)
+            .template_from_str(&template)
+            .context("Invalid Matrix template")?;
+        crate::create_template_env(ParseModeOpt::Html)
+            .template_from_str(&html_template)
+            .context("Invalid Matrix HTML template")?;
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        Ok(Self {
+            homeserver_url,
+            access_token: config.access_token.clone(),
+            room_ids: config.room_ids.clone(),
+            template,
+            html_template,
+            msgtype: if config.notice { "m.notice" } else { "m.text" },
+            messages: config.messages,
+            channels: config.channels.clone(),
+            policy,
+            txn_prefix: format!("emtt{}", started),
+            txn_counter: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn room_count(&self) -> usize {
+        self.room_ids.len()
+    }
+
+    fn send_url(&self, room_id: &str, txn_id: &str) -> Url {
+        let mut url = self.homeserver_url.clone();
+        url.path_segments_mut()
+            .expect("checked in compile")
+            .pop_if_empty()
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                room_id,
+                "send",
+                "m.room.message",
+                txn_id,
+            ]);
+        url
+    }
+
+    pub async fn deliver(&self, client: &Client, data: &MessageData) {
+        if !route_matches(self.messages, &self.channels, data) {
+            return;
+        }
+
+        let rendered = render(
+            &crate::create_template_env(ParseModeOpt::None),
+            &self.template,
+            data,
+        )
+        .and_then(|body| {
+            render(
+                &crate::create_template_env(ParseModeOpt::Html),
+                &self.html_template,
+                data,
+            )
+            .map(|html| (body, html))
+        });
+        let (body, formatted_body) = match rendered {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{}", fl!("failed-to-render", error = e.to_string()));
+                return;
+            }
+        };
+
+        let content = json!({
+            "msgtype": self.msgtype,
+            "body": body,
+            "format": "org.matrix.custom.html",
+            "formatted_body": formatted_body,
+        });
+
+        for room_id in &self.room_ids {
+            let txn = self.txn_counter.fetch_add(1, Ordering::Relaxed);
+            let url = self.send_url(room_id, &format!("{}-{}", self.txn_prefix, txn));
+
+            match send_with_retry(&self.policy, || {
+                client
+                    .put(url.clone())
+                    .bearer_auth(&self.access_token)
+                    .json(&content)
+            })
+            .await
+            {
+                Ok(_) => {
+                    metrics::counter!("emtt_sink_sends_total", "sink" => "matrix", "result" => "success")
+                        .increment(1);
+                    debug!(
+                        "{}",
+                        fl!(
+                            "forwarded-to-matrix",
+                            room = room_id.clone(),
+                            from = data.from.clone(),
+                            message = body.clone()
+                        )
+                    );
+                }
+                Err(err) => {
+                    metrics::counter!("emtt_sink_sends_total", "sink" => "matrix", "result" => "failure")
+                        .increment(1);
+                    warn!(
+                        "{}\n{}",
+                        fl!(
+                            "failed-to-send-matrix",
+                            room = room_id.clone(),
+                            error = err.to_string()
+                        ),
+                        fl!("message-content", content = body.clone())
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,270 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use log::{debug, info};
+use metrics::{describe_counter, describe_gauge};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+use crate::fl;
+
+// More deliveries in flight than this means the sinks can't keep up
+const OUTBOX_LIMIT: usize = 100;
+// Telegram deliveries hold up the syslog server, so one stuck for this long is a backlog too
+const STALL_LIMIT: Duration = Duration::from_secs(60);
+
+// Start times of the deliveries in flight, by a sequence number, so the first one is the oldest
+#[derive(Default)]
+struct Outbox {
+    next_id: u64,
+    started: BTreeMap<u64, Instant>,
+}
+
+struct HealthState {
+    started: Instant,
+    syslog_bound: AtomicBool,
+    // None when nothing is sent to Telegram
+    telegram_ready: Option<AtomicBool>,
+    outbox: Mutex<Outbox>,
+}
+
+// What the probes report, shared by the syslog server, the sinks and the bot
+#[derive(Clone)]
+pub struct Health {
+    state: Arc<HealthState>,
+}
+
+// A delivery in flight, counted in the outbox until dropped
+pub struct OutboxEntry {
+    state: Arc<HealthState>,
+    id: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum TelegramStatus {
+    Ready,
+    Connecting,
+    Disabled,
+}
+
+#[derive(Serialize)]
+struct Liveness {
+    status: &'static str,
+    uptime_seconds: u64,
+    syslog_bound: bool,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    syslog_bound: bool,
+    telegram: TelegramStatus,
+    outbox: usize,
+    outbox_limit: usize,
+    oldest_delivery_seconds: Option<u64>,
+    stall_limit_seconds: u64,
+}
+
+impl Health {
+    pub fn new(telegram: bool) -> Self {
+        Self {
+            state: Arc::new(HealthState {
+                started: Instant::now(),
+                syslog_bound: AtomicBool::new(false),
+                telegram_ready: telegram.then(|| AtomicBool::new(false)),
+                outbox: Mutex::default(),
+            }),
+        }
+    }
+
+    pub fn set_syslog_bound(&self) {
+        self.state.syslog_bound.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_telegram_ready(&self) {
+        if let Some(ready) = &self.state.telegram_ready {
+            ready.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn outbox_entry(&self) -> OutboxEntry {
+        let mut outbox = self.state.outbox.lock().unwrap();
+        let id = outbox.next_id;
+        outbox.next_id += 1;
+        outbox.started.insert(id, Instant::now());
+        metrics::gauge!("emtt_outbox_pending").set(outbox.started.len() as f64);
+        OutboxEntry {
+            state: self.state.clone(),
+            id,
+        }
+    }
+
+    fn liveness(&self) -> (StatusCode, Json<Liveness>) {
+        let syslog_bound = self.state.syslog_bound.load(Ordering::Relaxed);
+        let code = if syslog_bound {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let body = Liveness {
+            status: if syslog_bound { "ok" } else { "starting" },
+            uptime_seconds: self.state.started.elapsed().as_secs(),
+            syslog_bound,
+        };
+        (code, Json(body))
+    }
+
+    fn readiness(&self) -> (StatusCode, Json<Readiness>) {
+        let syslog_bound = self.state.syslog_bound.load(Ordering::Relaxed);
+        let telegram = match &self.state.telegram_ready {
+            Some(ready) if ready.load(Ordering::Relaxed) => TelegramStatus::Ready,
+            Some(_) => TelegramStatus::Connecting,
+            None => TelegramStatus::Disabled,
+        };
+        let (outbox, oldest) = {
+            let outbox = self.state.outbox.lock().unwrap();
+            let oldest = outbox.started.values().next().map(Instant::elapsed);
+            (outbox.started.len(), oldest)
+        };
+
+        let ready = syslog_bound
+            && !matches!(telegram, TelegramStatus::Connecting)
+            && outbox < OUTBOX_LIMIT
+            && oldest.is_none_or(|age| age < STALL_LIMIT);
+        let code = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let body = Readiness {
+            status: if ready { "ready" } else { "not_ready" },
+            syslog_bound,
+            telegram,
+            outbox,
+            outbox_limit: OUTBOX_LIMIT,
+            oldest_delivery_seconds: oldest.map(|age| age.as_secs()),
+            stall_limit_seconds: STALL_LIMIT.as_secs(),
+        };
+        (code, Json(body))
+    }
+}
+
+impl Drop for OutboxEntry {
+    fn drop(&mut self) {
+        let mut outbox = self.state.outbox.lock().unwrap();
+        outbox.started.remove(&self.id);
+        metrics::gauge!("emtt_outbox_pending").set(outbox.started.len() as f64);
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    metrics: PrometheusHandle,
+    health: Health,
+}
+
+// Until this is called the metrics macros record nothing
+pub fn install_recorder() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .install_recorder()
+        .context("Failed to install the metrics recorder")?;
+
+    describe_counter!(
+        "emtt_syslog_datagrams_total",
+        "Syslog datagrams received from gateways"
+    );
+    describe_counter!(
+        "emtt_syslog_parse_failures_total",
+        "Datagrams that couldn't be parsed, by error"
+    );
+    describe_counter!(
+        "emtt_text_messages_total",
+        "Text messages logged by gateways"
+    );
+    describe_counter!(
+        "emtt_text_messages_forwarded_total",
+        "Text messages passed on to the sinks"
+    );
+    describe_counter!(
+        "emtt_text_messages_ignored_total",
+        "Text messages that weren't forwarded, by reason"
+    );
+    describe_counter!(
+        "emtt_sink_sends_total",
+        "Deliveries to each sink, by result"
+    );
+    describe_counter!(
+        "emtt_http_retries_total",
+        "HTTP requests and emails retried after a failure"
+    );
+    describe_gauge!(
+        "emtt_handle_infos",
+        "Packets waiting to be matched with their text"
+    );
+    describe_gauge!("emtt_known_nodes", "Nodes heard on the mesh");
+    describe_gauge!(
+        "emtt_outbox_pending",
+        "Deliveries to the sinks that haven't finished yet"
+    );
+    describe_gauge!(
+        "emtt_gateway_last_packet_timestamp_seconds",
+        "Unix time of the last datagram from each gateway"
+    );
+
+    Ok(handle)
+}
+
+async fn metrics(State(state): State<AppState>) -> String {
+    state.metrics.render()
+}
+
+async fn healthz(State(state): State<AppState>) -> (StatusCode, Json<Liveness>) {
+    state.health.liveness()
+}
+
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    state.health.readiness()
+}
+
+pub async fn run_server(
+    subsys: SubsystemHandle,
+    addr: SocketAddr,
+    recorder: PrometheusHandle,
+    health: Health,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind HTTP server on {}", addr))?;
+    info!("{}", fl!("http-server-binding", addr = addr.to_string()));
+
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(AppState {
+            metrics: recorder,
+            health,
+        });
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { subsys.on_shutdown_requested().await })
+        .await
+        .context("HTTP server failed")?;
+
+    debug!("{}", fl!("http-server-shutdown"));
+    Ok(())
+}
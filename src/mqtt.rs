@@ -0,0 +1,286 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result, bail};
+use log::{debug, info, warn};
+use minijinja::{Environment, context};
+use rumqttc::{
+    AsyncClient, ClientError, Event as MqttEvent, EventLoop, MqttOptions, Packet, QoS,
+    TlsConfiguration, Transport,
+};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+use crate::events::Event;
+use crate::fl;
+use crate::homeassistant::{HomeAssistant, HomeAssistantConfig};
+use crate::payload::{CloudEvent, EnvelopeV2, NodeInfoData, PayloadFormat};
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_TLS_PORT: u16 = 8883;
+const DEFAULT_CLIENT_ID: &str = "emtt";
+const DEFAULT_TOPIC: &str = "emtt/messages";
+
+// Publishes queued while the broker is unreachable, extra ones are dropped
+const QUEUE_CAPACITY: usize = 100;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// QoS written as a number in the config file
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "u8")]
+pub struct QosLevel(QoS);
+
+impl Default for QosLevel {
+    fn default() -> Self {
+        Self(QoS::AtLeastOnce)
+    }
+}
+
+impl TryFrom<u8> for QosLevel {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        rumqttc::qos(value)
+            .map(Self)
+            .map_err(|_| format!("invalid QoS {}, expected 0, 1 or 2", value))
+    }
+}
+
+// The `[mqtt]` section of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    host: String,
+    port: Option<u16>,
+    #[serde(default)]
+    tls: bool,
+    client_id: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    topic: Option<String>,
+    nodeinfo_topic: Option<String>,
+    #[serde(default)]
+    format: PayloadFormat,
+    #[serde(default)]
+    qos: QosLevel,
+    #[serde(default)]
+    retain: bool,
+    homeassistant: Option<HomeAssistantConfig>,
+}
+
+#[derive(Clone)]
+pub struct MqttSink {
+    client: AsyncClient,
+    broker: String,
+    topic: String,
+    nodeinfo_topic: Option<String>,
+    format: PayloadFormat,
+    qos: QoS,
+    retain: bool,
+    homeassistant: Option<HomeAssistant>,
+}
+
+impl MqttSink {
+    pub fn new(config: &MqttConfig) -> Result<(Self, EventLoop)> {
+        if matches!(config.format, PayloadFormat::CloudEventsBinary) {
+            bail!("MQTT messages have no headers for the binary CloudEvents mode");
+        }
+
+        let topic = config
+            .topic
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TOPIC.to_string());
+        let env = Environment::new();
+        for t in std::iter::once(&topic).chain(config.nodeinfo_topic.as_ref()) {
+            env.template_from_str(t)
+                .with_context(|| format!("Invalid MQTT topic {}", t))?;
+        }
+
+        let port = config.port.unwrap_or(if config.tls {
+            DEFAULT_TLS_PORT
+        } else {
+            DEFAULT_PORT
+        });
+        let client_id = config
+            .client_id
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CLIENT_ID.to_string());
+
+        let mut options = MqttOptions::new(client_id, config.host.clone(), port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if let Some(username) = &config.username {
+            options.set_credentials(
+                username.clone(),
+                config.password.clone().unwrap_or_default(),
+            );
+        }
+        if config.tls {
+            options.set_transport(Transport::Tls(TlsConfiguration::Native));
+        }
+
+        let (client, eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+
+        Ok((
+            Self {
+                client,
+                broker: format!("{}:{}", config.host, port),
+                topic,
+                nodeinfo_topic: config.nodeinfo_topic.clone(),
+                format: config.format,
+                qos: config.qos.0,
+                retain: config.retain,
+                homeassistant: config.homeassistant.as_ref().map(HomeAssistant::new),
+            },
+            eventloop,
+        ))
+    }
+
+    pub fn broker(&self) -> &str {
+        &self.broker
+    }
+
+    pub fn homeassistant_enabled(&self) -> bool {
+        self.homeassistant.is_some()
+    }
+
+    fn render_topic(&self, template: &str, event: &Event) -> Result<String> {
+        let ctx = match event {
+            Event::Text(data) => context! {
+                event => "text",
+                via => data.via.clone(),
+                from_id => format!("{:08x}", data.from_id),
+                channel => data.channel,
+                direct => data.is_direct(),
+            },
+            Event::NodeInfo(info) => context! {
+                event => "nodeinfo",
+                via => info.via.clone(),
+                from_id => format!("{:08x}", info.node_id),
+            },
+            Event::Telemetry(telemetry) => context! {
+                event => "telemetry",
+                via => telemetry.via.clone(),
+            },
+            Event::Ignored(ignored) => context! {
+                event => "ignored",
+                via => ignored.via.clone(),
+            },
+        };
+        Ok(Environment::new().render_str(template, ctx)?)
+    }
+
+    fn payload(&self, event: &Event) -> Result<Option<(String, String)>> {
+        let (topic, payload) = match (event, self.format) {
+            (Event::Text(data), PayloadFormat::V1) => (&self.topic, serde_json::to_string(data)?),
+            (Event::Text(data), PayloadFormat::V2) => {
+                (&self.topic, serde_json::to_string(&EnvelopeV2::text(data))?)
+            }
+            (Event::Text(_), _) => (
+                &self.topic,
+                serde_json::to_string(&CloudEvent::new(event)?)?,
+            ),
+            (Event::NodeInfo(info), format) => {
+                let Some(topic) = &self.nodeinfo_topic else {
+                    return Ok(None);
+                };
+                let payload = match format {
+                    PayloadFormat::V1 | PayloadFormat::V2 => {
+                        serde_json::to_string(&NodeInfoData::from(info))?
+                    }
+                    _ => serde_json::to_string(&CloudEvent::new(event)?)?,
+                };
+                (topic, payload)
+            }
+            (Event::Telemetry(_) | Event::Ignored(_), _) => return Ok(None),
+        };
+        Ok(Some((self.render_topic(topic, event)?, payload)))
+    }
+
+    pub fn publish(&self, event: &Event) {
+        if let Some(homeassistant) = &self.homeassistant {
+            homeassistant.publish(self, event);
+        }
+
+        match self.payload(event) {
+            Ok(Some((topic, payload))) => {
+                self.send(topic, payload, self.retain);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("{}", fl!("failed-to-render", error = e.to_string())),
+        }
+    }
+
+    // False when the message couldn't be queued and was dropped
+    pub fn send(&self, topic: String, payload: String, retain: bool) -> bool {
+        // Never wait for the broker here, it would hold up the syslog server
+        match self
+            .client
+            .try_publish(topic.clone(), self.qos, retain, payload.clone())
+        {
+            Ok(()) => {
+                metrics::counter!("emtt_sink_sends_total", "sink" => "mqtt", "result" => "success")
+                    .increment(1);
+                debug!(
+                    "{}",
+                    fl!("published-to-mqtt", topic = topic, message = payload)
+                );
+                true
+            }
+            Err(err) => {
+                metrics::counter!("emtt_sink_sends_total", "sink" => "mqtt", "result" => "failure")
+                    .increment(1);
+                let error = match err {
+                    ClientError::TryRequest(_) => fl!("mqtt-queue-full"),
+                    e => e.to_string(),
+                };
+                warn!(
+                    "{}",
+                    fl!("failed-to-publish-mqtt", topic = topic, error = error)
+                );
+                false
+            }
+        }
+    }
+}
+
+// Drives the MQTT connection, rumqttc reconnects on the next poll after an error
+pub async fn run_eventloop(
+    subsys: SubsystemHandle,
+    mut eventloop: EventLoop,
+    broker: String,
+) -> Result<()> {
+    // Only the first error after a successful connection is a warning, retries are logged quietly
+    let mut error_reported = false;
+    loop {
+        tokio::select! {
+            res = eventloop.poll() => match res {
+                Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                    error_reported = false;
+                    info!("{}", fl!("mqtt-connected", broker = broker.clone()));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let message = fl!("mqtt-connection-error", broker = broker.clone(), error = e.to_string());
+                    if error_reported {
+                        debug!("{}", message);
+                    } else {
+                        warn!("{}", message);
+                        error_reported = true;
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                        _ = subsys.on_shutdown_requested() => break,
+                    }
+                }
+            },
+            _ = subsys.on_shutdown_requested() => break,
+        }
+    }
+
+    debug!("{}", fl!("mqtt-shutdown"));
+    Ok(())
+}
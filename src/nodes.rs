@@ -0,0 +1,226 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// How many receptions are kept per node for signal history
+const HISTORY_SIZE: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct NodeInfo {
+    pub shortname: String,
+    pub longname: String,
+}
+
+// Case and surrounding spaces don't make a name look any different in a chat
+fn same(a: &str, b: &str) -> bool {
+    let (a, b) = (a.trim(), b.trim());
+    !a.is_empty() && a.to_lowercase() == b.to_lowercase()
+}
+
+impl NodeInfo {
+    // Either name is enough to be mistaken for another node
+    pub fn same_name(&self, other: &NodeInfo) -> bool {
+        same(&self.longname, &other.longname) || same(&self.shortname, &other.shortname)
+    }
+
+    // Both names read the same, e.g. after fixing the case
+    pub fn looks_like(&self, other: &NodeInfo) -> bool {
+        same(&self.longname, &other.longname) && same(&self.shortname, &other.shortname)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Reception {
+    pub timestamp: u64,
+    pub via: String,
+    pub snr: Option<f32>,
+    pub rssi: Option<i32>,
+    pub hops_away: Option<i32>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Node {
+    pub info: Option<NodeInfo>,
+    // When the node first announced its names, and when it last changed them
+    pub first_seen: Option<u64>,
+    pub renamed_at: Option<u64>,
+    pub history: VecDeque<Reception>,
+}
+
+// How a node info announcement changed what's known about the node
+pub enum InfoChange {
+    New,
+    Renamed(NodeInfo),
+}
+
+impl Node {
+    pub fn last_seen(&self) -> Option<&Reception> {
+        self.history.back()
+    }
+}
+
+// Nodes heard on the mesh, shared between the syslog server and the bot
+#[derive(Clone, Default)]
+pub struct NodeStore {
+    nodes: Arc<Mutex<HashMap<u32, Node>>>,
+}
+
+impl NodeStore {
+    // Starts with nodes remembered from an earlier run
+    pub fn new(nodes: HashMap<u32, Node>) -> Self {
+        metrics::gauge!("emtt_known_nodes").set(nodes.len() as f64);
+        Self {
+            nodes: Arc::new(Mutex::new(nodes)),
+        }
+    }
+
+    pub async fn update_info(&self, id: u32, info: NodeInfo, at: u64) -> Option<InfoChange> {
+        let mut nodes = self.nodes.lock().await;
+        let node = nodes.entry(id).or_default();
+        let change = match &node.info {
+            None => {
+                node.first_seen = Some(at);
+                InfoChange::New
+            }
+            Some(previous)
+                if previous.longname != info.longname || previous.shortname != info.shortname =>
+            {
+                // Only a name that reads differently can be used to impersonate someone
+                if !previous.looks_like(&info) {
+                    node.renamed_at = Some(at);
+                }
+                InfoChange::Renamed(previous.clone())
+            }
+            Some(_) => return None,
+        };
+        node.info = Some(info);
+        metrics::gauge!("emtt_known_nodes").set(nodes.len() as f64);
+        Some(change)
+    }
+
+    pub async fn record_reception(&self, id: u32, reception: Reception) {
+        let mut nodes = self.nodes.lock().await;
+        let history = &mut nodes.entry(id).or_default().history;
+        history.push_back(reception);
+        while history.len() > HISTORY_SIZE {
+            history.pop_front();
+        }
+        metrics::gauge!("emtt_known_nodes").set(nodes.len() as f64);
+    }
+
+    pub async fn get(&self, id: u32) -> Option<Node> {
+        self.nodes.lock().await.get(&id).cloned()
+    }
+
+    // Another node that announced the same long or short name
+    pub async fn same_name(&self, id: u32) -> Option<(u32, NodeInfo)> {
+        let nodes = self.nodes.lock().await;
+        let info = nodes.get(&id)?.info.as_ref()?;
+        nodes.iter().find_map(|(other_id, other)| {
+            let other_info = other.info.as_ref()?;
+            (*other_id != id && info.same_name(other_info)).then(|| (*other_id, other_info.clone()))
+        })
+    }
+
+    // Nodes that announced their names, for saving the roster
+    pub async fn named(&self) -> Vec<(u32, Node)> {
+        self.nodes
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, node)| node.info.is_some())
+            .map(|(id, node)| (*id, node.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(longname: &str, shortname: &str) -> NodeInfo {
+        NodeInfo {
+            shortname: shortname.to_string(),
+            longname: longname.to_string(),
+        }
+    }
+
+    #[test]
+    fn same_name_ignores_case_and_spaces() {
+        let alice = info("Alice", "AL");
+        assert!(alice.same_name(&info(" alice ", "XY")));
+        assert!(alice.same_name(&info("Bob", "al ")));
+        assert!(!alice.same_name(&info("Alicia", "AC")));
+        // Empty names don't make nodes look alike
+        assert!(!info("", "").same_name(&info(" ", "")));
+    }
+
+    #[test]
+    fn looks_like_needs_both_names() {
+        let alice = info("Alice", "AL");
+        assert!(alice.looks_like(&info("ALICE ", " al")));
+        assert!(!alice.looks_like(&info("Mallory", "AL")));
+        assert!(!alice.looks_like(&info("Alice", "MA")));
+    }
+
+    #[tokio::test]
+    async fn tracks_new_nodes_and_renames() {
+        let store = NodeStore::default();
+        assert!(matches!(
+            store.update_info(1, info("Alice", "AL"), 100).await,
+            Some(InfoChange::New)
+        ));
+        assert!(
+            store
+                .update_info(1, info("Alice", "AL"), 200)
+                .await
+                .is_none()
+        );
+
+        let node = store.get(1).await.unwrap();
+        assert_eq!(node.first_seen, Some(100));
+        assert_eq!(node.renamed_at, None);
+
+        match store.update_info(1, info("Mallory", "MA"), 300).await {
+            Some(InfoChange::Renamed(previous)) => assert_eq!(previous.longname, "Alice"),
+            _ => panic!("expected a rename"),
+        }
+        let node = store.get(1).await.unwrap();
+        assert_eq!(node.first_seen, Some(100));
+        assert_eq!(node.renamed_at, Some(300));
+    }
+
+    #[tokio::test]
+    async fn fixing_the_case_is_not_a_rename() {
+        let store = NodeStore::default();
+        store.update_info(1, info("alice", "al"), 100).await;
+        assert!(matches!(
+            store.update_info(1, info("Alice", "AL"), 200).await,
+            Some(InfoChange::Renamed(_))
+        ));
+        assert_eq!(store.get(1).await.unwrap().renamed_at, None);
+    }
+
+    #[tokio::test]
+    async fn finds_other_nodes_with_the_same_name() {
+        let store = NodeStore::default();
+        store.update_info(1, info("Alice", "AL"), 100).await;
+        store.update_info(2, info("Bob", "BB"), 100).await;
+        assert!(store.same_name(1).await.is_none());
+
+        store.update_info(2, info("ALICE", "BB"), 200).await;
+        let (other_id, other) = store.same_name(2).await.unwrap();
+        assert_eq!(other_id, 1);
+        assert_eq!(other.longname, "Alice");
+
+        // A node is never mistaken for itself, whatever it was called before
+        store.update_info(3, info("Carol", "CA"), 100).await;
+        store.update_info(3, info("carol", "CA"), 200).await;
+        assert!(store.same_name(3).await.is_none());
+        assert!(store.same_name(4).await.is_none());
+    }
+}
@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result, bail};
+use chrono::{Local, NaiveTime, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::MessageData;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    always_notify: Vec<String>,
+    silent: Vec<SilentRuleConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SilentRuleConfig {
+    #[serde(default)]
+    chat_ids: Vec<i64>,
+    #[serde(default)]
+    messages: MessageKind,
+    from: Option<String>,
+    to: Option<String>,
+    timezone: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    #[default]
+    All,
+    Dm,
+    Channel,
+}
+
+impl MessageKind {
+    pub fn matches(self, data: &MessageData) -> bool {
+        match self {
+            MessageKind::All => true,
+            MessageKind::Dm => data.is_direct(),
+            MessageKind::Channel => !data.is_direct(),
+        }
+    }
+}
+
+// Message kind and channel filters of sink routes, channels don't apply to direct messages
+pub fn route_matches(messages: MessageKind, channels: &[u32], data: &MessageData) -> bool {
+    messages.matches(data)
+        && (data.is_direct() || channels.is_empty() || channels.contains(&data.channel))
+}
+
+#[derive(Clone)]
+struct SilentRule {
+    chat_ids: Vec<i64>,
+    messages: MessageKind,
+    window: Option<(NaiveTime, NaiveTime)>,
+    timezone: Option<Tz>,
+}
+
+// Decides whether a Telegram message is sent with `disable_notification`
+#[derive(Clone, Default)]
+pub struct NotificationRules {
+    always_notify: Option<Regex>,
+    silent: Vec<SilentRule>,
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .with_context(|| format!("Invalid time '{}', expected HH:MM", value))
+}
+
+// Case-insensitive match of any keyword as a separate word
+pub fn keyword_regex(keywords: &[String]) -> Result<Regex, regex::Error> {
+    let keywords = keywords
+        .iter()
+        .map(|k| regex::escape(k))
+        .collect::<Vec<_>>()
+        .join("|");
+    Regex::new(&format!(r"(?i)(?:^|\W)(?:{})(?:\W|$)", keywords))
+}
+
+impl SilentRule {
+    fn compile(config: &SilentRuleConfig) -> Result<Self> {
+        let window = match (&config.from, &config.to) {
+            (Some(from), Some(to)) => Some((parse_time(from)?, parse_time(to)?)),
+            (None, None) => None,
+            _ => bail!("Both 'from' and 'to' must be set for quiet hours"),
+        };
+
+        let timezone = match &config.timezone {
+            Some(tz) => Some(
+                tz.parse::<Tz>()
+                    .map_err(|e| anyhow::anyhow!("Invalid timezone '{}': {}", tz, e))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            chat_ids: config.chat_ids.clone(),
+            messages: config.messages,
+            window,
+            timezone,
+        })
+    }
+
+    fn matches(&self, chat_id: i64, data: &MessageData) -> bool {
+        if !self.chat_ids.is_empty() && !self.chat_ids.contains(&chat_id) {
+            return false;
+        }
+
+        if !self.messages.matches(data) {
+            return false;
+        }
+
+        let Some((from, to)) = self.window else {
+            return true;
+        };
+
+        let now = match self.timezone {
+            Some(tz) => Utc::now().with_timezone(&tz).time(),
+            None => Local::now().time(),
+        };
+
+        in_window(from, to, now)
+    }
+}
+
+fn in_window(from: NaiveTime, to: NaiveTime, now: NaiveTime) -> bool {
+    if from < to {
+        from <= now && now < to
+    } else if from > to {
+        // The window wraps past midnight, e.g. 22:00-08:00
+        now >= from || now < to
+    } else {
+        true
+    }
+}
+
+impl NotificationRules {
+    pub fn compile(config: &NotificationsConfig) -> Result<Self> {
+        let always_notify = if config.always_notify.is_empty() {
+            None
+        } else {
+            Some(keyword_regex(&config.always_notify)?)
+        };
+
+        let silent = config
+            .silent
+            .iter()
+            .map(SilentRule::compile)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            always_notify,
+            silent,
+        })
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.silent.len()
+    }
+
+    pub fn is_silent(&self, chat_id: i64, data: &MessageData) -> bool {
+        if let Some(re) = &self.always_notify
+            && re.is_match(&data.text)
+        {
+            return false;
+        }
+
+        self.silent.iter().any(|rule| rule.matches(chat_id, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(value: &str) -> NaiveTime {
+        parse_time(value).unwrap()
+    }
+
+    #[test]
+    fn daytime_window() {
+        assert!(in_window(t("09:00"), t("18:00"), t("09:00")));
+        assert!(in_window(t("09:00"), t("18:00"), t("12:30")));
+        assert!(!in_window(t("09:00"), t("18:00"), t("18:00")));
+        assert!(!in_window(t("09:00"), t("18:00"), t("03:00")));
+    }
+
+    #[test]
+    fn overnight_window() {
+        assert!(in_window(t("22:00"), t("08:00"), t("22:00")));
+        assert!(in_window(t("22:00"), t("08:00"), t("23:59")));
+        assert!(in_window(t("22:00"), t("08:00"), t("00:00")));
+        assert!(in_window(t("22:00"), t("08:00"), t("07:59")));
+        assert!(!in_window(t("22:00"), t("08:00"), t("08:00")));
+        assert!(!in_window(t("22:00"), t("08:00"), t("12:00")));
+    }
+
+    #[test]
+    fn equal_bounds_cover_the_whole_day() {
+        assert!(in_window(t("00:00"), t("00:00"), t("13:00")));
+    }
+
+    #[test]
+    fn rejects_malformed_time() {
+        assert!(parse_time("25:00").is_err());
+        assert!(parse_time("8am").is_err());
+    }
+
+    #[test]
+    fn keywords_match_whole_words() {
+        let re = keyword_regex(&["SOS".to_string(), "a.b".to_string()]).unwrap();
+        assert!(re.is_match("sos"));
+        assert!(re.is_match("Need help, SOS!"));
+        assert!(!re.is_match("sosiska"));
+        assert!(re.is_match("see a.b here"));
+        assert!(!re.is_match("see axb here"));
+    }
+}
@@ -0,0 +1,431 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chrono::{DateTime, SecondsFormat};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::events::{Event, IgnoreReason, IgnoredEvent, NodeInfoEvent};
+use crate::{MessageData, fl};
+
+const ENVELOPE_VERSION: u32 = 2;
+
+// JSON shape of messages sent to webhooks
+#[derive(Clone, Copy, Debug, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    // The bare message fields, as in EMtT 1.x
+    #[default]
+    #[value(name = "v1", help = fl!("payload-format-v1"))]
+    V1,
+    #[value(name = "v2", help = fl!("payload-format-v2"))]
+    V2,
+    // CloudEvents 1.0, structured content mode: attributes and data in the body
+    #[serde(rename = "cloudevents")]
+    #[value(name = "cloudevents", help = fl!("payload-format-cloudevents"))]
+    CloudEvents,
+    // CloudEvents 1.0, binary content mode: attributes in ce-* headers, data in the body
+    #[serde(rename = "cloudevents-binary")]
+    #[value(name = "cloudevents-binary", help = fl!("payload-format-cloudevents-binary"))]
+    CloudEventsBinary,
+}
+
+impl PayloadFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            PayloadFormat::V1 => "v1",
+            PayloadFormat::V2 => "v2",
+            PayloadFormat::CloudEvents => "cloudevents",
+            PayloadFormat::CloudEventsBinary => "cloudevents-binary",
+        }
+    }
+
+    // Node info and telemetry only fit into CloudEvents, v1 and v2 carry text messages.
+    // Ignored messages are only archived.
+    pub fn accepts(self, event: &Event) -> bool {
+        match (self, event) {
+            (_, Event::Ignored(_)) => false,
+            (PayloadFormat::V1 | PayloadFormat::V2, _) => matches!(event, Event::Text(_)),
+            (PayloadFormat::CloudEvents | PayloadFormat::CloudEventsBinary, _) => true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeRef {
+    pub id: u32,
+    pub id_hex: String,
+    pub shortname: Option<String>,
+    pub longname: Option<String>,
+}
+
+impl NodeRef {
+    fn new(id: u32) -> Self {
+        Self {
+            id,
+            id_hex: format!("!{:08x}", id),
+            shortname: None,
+            longname: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Gateway {
+    pub via: String,
+    pub snr: Option<f32>,
+    pub rssi: Option<i32>,
+    pub hops_away: Option<i32>,
+    pub received_at: u64,
+    pub rx_time: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmttInfo {
+    pub version: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnvelopeV2 {
+    pub version: u32,
+    pub event: &'static str,
+    pub id: u32,
+    pub timestamp: u64,
+    pub from: NodeRef,
+    pub to: NodeRef,
+    pub direct: bool,
+    pub channel: u32,
+    pub reply_id: Option<u32>,
+    pub is_reaction: bool,
+    // The sender's name matches another node's or was changed recently
+    pub is_name_conflict: bool,
+    pub text: String,
+    pub gateway: Gateway,
+    pub emtt: EmttInfo,
+}
+
+impl EnvelopeV2 {
+    pub fn text(data: &MessageData) -> Self {
+        let mut from = NodeRef::new(data.from_id);
+        from.shortname = data.shortname.clone();
+        from.longname = data.longname.clone();
+
+        Self {
+            version: ENVELOPE_VERSION,
+            event: "text",
+            id: data.id,
+            timestamp: data.received_at,
+            from,
+            to: NodeRef::new(data.to),
+            direct: data.is_direct(),
+            channel: data.channel,
+            reply_id: data.reply_id,
+            is_reaction: data.is_reaction,
+            is_name_conflict: data.is_name_conflict,
+            text: data.text.clone(),
+            // Every gateway reception is delivered as its own envelope
+            gateway: Gateway {
+                via: data.via.clone(),
+                snr: data.snr,
+                rssi: data.rssi,
+                hops_away: data.hops_away,
+                received_at: data.received_at,
+                rx_time: data.rx_time,
+            },
+            emtt: EmttInfo {
+                version: env!("CARGO_PKG_VERSION"),
+            },
+        }
+    }
+}
+
+const CLOUDEVENTS_SPEC_VERSION: &str = "1.0";
+const CLOUDEVENTS_TYPE_PREFIX: &str = "com.github.black-roland.emtt.";
+
+// Payload of nodeinfo and telemetry CloudEvents
+#[derive(Debug, Serialize)]
+pub struct NodeInfoData {
+    pub node: NodeRef,
+    pub via: String,
+    pub received_at: u64,
+}
+
+impl From<&NodeInfoEvent> for NodeInfoData {
+    fn from(info: &NodeInfoEvent) -> Self {
+        let mut node = NodeRef::new(info.node_id);
+        node.shortname = Some(info.shortname.clone());
+        node.longname = Some(info.longname.clone());
+        Self {
+            node,
+            via: info.via.clone(),
+            received_at: info.received_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TelemetryData {
+    pub from: Option<NodeRef>,
+    pub sender: Option<String>,
+    pub local: bool,
+    pub metrics: BTreeMap<String, f64>,
+    pub via: String,
+    pub received_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IgnoredData {
+    pub from: NodeRef,
+    pub to: Option<NodeRef>,
+    pub channel: Option<u32>,
+    pub text: String,
+    pub reason: IgnoreReason,
+    pub via: String,
+    pub received_at: u64,
+}
+
+impl From<&IgnoredEvent> for IgnoredData {
+    fn from(ignored: &IgnoredEvent) -> Self {
+        Self {
+            from: NodeRef::new(ignored.from_id),
+            to: ignored.to.map(NodeRef::new),
+            channel: ignored.channel,
+            text: ignored.text.clone(),
+            reason: ignored.reason,
+            via: ignored.via.clone(),
+            received_at: ignored.received_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CloudEvent {
+    pub specversion: &'static str,
+    pub id: String,
+    pub source: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    pub time: String,
+    pub datacontenttype: &'static str,
+    pub data: serde_json::Value,
+}
+
+impl CloudEvent {
+    pub fn new(event: &Event) -> serde_json::Result<Self> {
+        let (kind, id, node_id, via, received_at, data) = match event {
+            Event::Text(data) => (
+                "text",
+                Some(data.id),
+                Some(data.from_id),
+                &data.via,
+                data.received_at,
+                serde_json::to_value(EnvelopeV2::text(data))?,
+            ),
+            Event::NodeInfo(info) => (
+                "nodeinfo",
+                info.id,
+                Some(info.node_id),
+                &info.via,
+                info.received_at,
+                serde_json::to_value(NodeInfoData::from(info))?,
+            ),
+            Event::Telemetry(telemetry) => {
+                let data = TelemetryData {
+                    from: telemetry.from_id.map(NodeRef::new),
+                    sender: telemetry.sender.clone(),
+                    local: telemetry.is_local(),
+                    metrics: telemetry.metrics.clone(),
+                    via: telemetry.via.clone(),
+                    received_at: telemetry.received_at,
+                };
+                (
+                    "telemetry",
+                    telemetry.id,
+                    telemetry.from_id,
+                    &telemetry.via,
+                    telemetry.received_at,
+                    serde_json::to_value(data)?,
+                )
+            }
+            Event::Ignored(ignored) => (
+                "ignored",
+                Some(ignored.id),
+                Some(ignored.from_id),
+                &ignored.via,
+                ignored.received_at,
+                serde_json::to_value(IgnoredData::from(ignored))?,
+            ),
+        };
+
+        // Without a packet ID (e.g. the gateway's own telemetry), the event is unique per gateway and second
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => format!("{}-{}-{}", kind, via, received_at),
+        };
+
+        Ok(Self {
+            specversion: CLOUDEVENTS_SPEC_VERSION,
+            id,
+            source: format!("/emtt/gateways/{}", via),
+            event_type: format!("{}{}", CLOUDEVENTS_TYPE_PREFIX, kind),
+            subject: node_id.map(|id| format!("!{:08x}", id)),
+            time: DateTime::from_timestamp(received_at as i64, 0)
+                .unwrap_or_default()
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            datacontenttype: "application/json",
+            data,
+        })
+    }
+
+    // Attributes as HTTP headers for the binary content mode
+    pub fn binary_headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("ce-specversion", self.specversion.to_string()),
+            ("ce-id", self.id.clone()),
+            ("ce-source", self.source.clone()),
+            ("ce-type", self.event_type.clone()),
+            ("ce-time", self.time.clone()),
+        ];
+        if let Some(subject) = &self.subject {
+            headers.push(("ce-subject", subject.clone()));
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::TelemetryEvent;
+
+    fn text() -> Event {
+        Event::Text(MessageData {
+            id: 1234,
+            from_id: 0xdeadbeef,
+            via: "gw1".to_string(),
+            text: "hello".to_string(),
+            received_at: 1714564800,
+            ..Default::default()
+        })
+    }
+
+    fn nodeinfo() -> Event {
+        Event::NodeInfo(NodeInfoEvent {
+            id: Some(42),
+            node_id: 0x0000abcd,
+            shortname: "AB".to_string(),
+            longname: "Alpha Bravo".to_string(),
+            via: "gw1".to_string(),
+            received_at: 1714564800,
+        })
+    }
+
+    fn local_telemetry() -> Event {
+        Event::Telemetry(TelemetryEvent {
+            id: None,
+            from_id: None,
+            sender: None,
+            metrics: BTreeMap::from([("battery_level".to_string(), 100.0)]),
+            via: "gw1".to_string(),
+            received_at: 1714564800,
+        })
+    }
+
+    fn ignored() -> Event {
+        Event::Ignored(IgnoredEvent {
+            id: 7,
+            from_id: 1,
+            text: "seq 1".to_string(),
+            reason: IgnoreReason::RangeTest,
+            to: None,
+            channel: Some(0),
+            via: "gw1".to_string(),
+            received_at: 1714564800,
+        })
+    }
+
+    #[test]
+    fn cloudevent_has_required_attributes() {
+        let event = CloudEvent::new(&text()).unwrap();
+        assert_eq!(event.specversion, "1.0");
+        assert_eq!(event.id, "1234");
+        assert_eq!(event.source, "/emtt/gateways/gw1");
+        assert_eq!(event.event_type, "com.github.black-roland.emtt.text");
+        assert_eq!(event.subject.as_deref(), Some("!deadbeef"));
+        assert_eq!(event.time, "2024-05-01T12:00:00Z");
+        assert_eq!(event.datacontenttype, "application/json");
+
+        let json = serde_json::to_value(&event).unwrap();
+        for attribute in ["specversion", "id", "source", "type"] {
+            assert!(json.get(attribute).is_some(), "missing {}", attribute);
+        }
+        assert_eq!(json["data"]["text"], "hello");
+    }
+
+    #[test]
+    fn cloudevent_type_follows_event_kind() {
+        let event = CloudEvent::new(&nodeinfo()).unwrap();
+        assert_eq!(event.id, "42");
+        assert_eq!(event.event_type, "com.github.black-roland.emtt.nodeinfo");
+        assert_eq!(event.subject.as_deref(), Some("!0000abcd"));
+    }
+
+    #[test]
+    fn cloudevent_without_packet_id_gets_fallback_id() {
+        let event = CloudEvent::new(&local_telemetry()).unwrap();
+        assert_eq!(event.id, "telemetry-gw1-1714564800");
+        assert_eq!(event.event_type, "com.github.black-roland.emtt.telemetry");
+        assert_eq!(event.subject, None);
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert!(json.get("subject").is_none());
+        assert_eq!(json["data"]["local"], true);
+    }
+
+    #[test]
+    fn binary_headers_map_attributes() {
+        let event = CloudEvent::new(&text()).unwrap();
+        assert_eq!(
+            event.binary_headers(),
+            vec![
+                ("ce-specversion", "1.0".to_string()),
+                ("ce-id", "1234".to_string()),
+                ("ce-source", "/emtt/gateways/gw1".to_string()),
+                ("ce-type", "com.github.black-roland.emtt.text".to_string()),
+                ("ce-time", "2024-05-01T12:00:00Z".to_string()),
+                ("ce-subject", "!deadbeef".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_headers_skip_missing_subject() {
+        let event = CloudEvent::new(&local_telemetry()).unwrap();
+        let headers = event.binary_headers();
+        assert_eq!(headers.len(), 5);
+        assert!(headers.iter().all(|(name, _)| *name != "ce-subject"));
+    }
+
+    #[test]
+    fn formats_accept_their_event_kinds() {
+        let (text, nodeinfo, telemetry, ignored) =
+            (text(), nodeinfo(), local_telemetry(), ignored());
+
+        for format in [PayloadFormat::V1, PayloadFormat::V2] {
+            assert!(format.accepts(&text));
+            assert!(!format.accepts(&nodeinfo));
+            assert!(!format.accepts(&telemetry));
+            assert!(!format.accepts(&ignored));
+        }
+        for format in [PayloadFormat::CloudEvents, PayloadFormat::CloudEventsBinary] {
+            assert!(format.accepts(&text));
+            assert!(format.accepts(&nodeinfo));
+            assert!(format.accepts(&telemetry));
+            assert!(!format.accepts(&ignored));
+        }
+    }
+}
@@ -0,0 +1,355 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result, bail};
+use log::{debug, warn};
+use minijinja::context;
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::json;
+use std::ops::RangeInclusive;
+
+use crate::http::{RetryPolicy, send_with_retry};
+use crate::notifications::{MessageKind, route_matches};
+use crate::{MessageData, ParseModeOpt, fl};
+
+const DEFAULT_NTFY_SERVER: &str = "https://ntfy.sh";
+const DEFAULT_TITLE: &str = "{% if alert %}{{ alert }}: {% endif %}{{ from }} (via {{ via }})";
+const DEFAULT_TEMPLATE: &str = "{{ text }}";
+
+// ntfy priorities go from 1 (min) to 5 (max), 3 is the default one
+const NTFY_PRIORITY: u8 = 3;
+const NTFY_ALERT_PRIORITY: u8 = 5;
+const NTFY_PRIORITIES: RangeInclusive<u8> = 1..=5;
+
+// Gotify clients show priorities above 7 as high
+const GOTIFY_PRIORITY: u8 = 5;
+const GOTIFY_ALERT_PRIORITY: u8 = 8;
+const GOTIFY_PRIORITIES: RangeInclusive<u8> = 0..=10;
+
+// A `[[ntfy]]` route of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NtfyConfig {
+    server: Option<String>,
+    topic: String,
+    token: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    title: Option<String>,
+    template: Option<String>,
+    priority: Option<u8>,
+    alert_priority: Option<u8>,
+    #[serde(default)]
+    messages: MessageKind,
+    #[serde(default)]
+    channels: Vec<u32>,
+}
+
+// A `[[gotify]]` route of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GotifyConfig {
+    server: String,
+    token: String,
+    title: Option<String>,
+    template: Option<String>,
+    priority: Option<u8>,
+    alert_priority: Option<u8>,
+    #[serde(default)]
+    messages: MessageKind,
+    #[serde(default)]
+    channels: Vec<u32>,
+}
+
+#[derive(Clone)]
+enum NtfyAuth {
+    None,
+    Token(String),
+    Basic(String, String),
+}
+
+#[derive(Clone)]
+enum Service {
+    Ntfy {
+        server: String,
+        topic: String,
+        auth: NtfyAuth,
+        tags: Vec<String>,
+    },
+    Gotify {
+        url: String,
+        token: String,
+    },
+}
+
+#[derive(Clone)]
+struct PushRoute {
+    service: Service,
+    title: String,
+    template: String,
+    priority: u8,
+    alert_priority: u8,
+    messages: MessageKind,
+    channels: Vec<u32>,
+}
+
+#[derive(Clone)]
+pub struct PushSink {
+    routes: Vec<PushRoute>,
+    policy: RetryPolicy,
+}
+
+fn priority(value: Option<u8>, default: u8, valid: RangeInclusive<u8>, target: &str) -> Result<u8> {
+    match value {
+        Some(p) if !valid.contains(&p) => bail!(
+            "Priority {} for {} is out of range {}-{}",
+            p,
+            target,
+            valid.start(),
+            valid.end()
+        ),
+        Some(p) => Ok(p),
+        None => Ok(default),
+    }
+}
+
+impl Service {
+    fn name(&self) -> &'static str {
+        match self {
+            Service::Ntfy { .. } => "ntfy",
+            Service::Gotify { .. } => "gotify",
+        }
+    }
+
+    fn request(&self, client: &Client, title: &str, message: &str, priority: u8) -> RequestBuilder {
+        match self {
+            // JSON publishing goes to the server root, the topic is in the body
+            Service::Ntfy {
+                server,
+                topic,
+                auth,
+                tags,
+            } => {
+                let req = client.post(server).json(&json!({
+                    "topic": topic,
+                    "title": title,
+                    "message": message,
+                    "priority": priority,
+                    "tags": tags,
+                }));
+                match auth {
+                    NtfyAuth::None => req,
+                    NtfyAuth::Token(token) => req.bearer_auth(token),
+                    NtfyAuth::Basic(username, password) => req.basic_auth(username, Some(password)),
+                }
+            }
+            Service::Gotify { url, token } => {
+                client.post(url).header("X-Gotify-Key", token).json(&json!({
+                    "title": title,
+                    "message": message,
+                    "priority": priority,
+                }))
+            }
+        }
+    }
+}
+
+impl PushRoute {
+    fn new(
+        service: Service,
+        title: &Option<String>,
+        template: &Option<String>,
+        priority: u8,
+        alert_priority: u8,
+        messages: MessageKind,
+        channels: &[u32],
+    ) -> Result<Self> {
+        let title = title
+            .clone()
+            .map(crate::unescape_template)
+            .unwrap_or_else(|| DEFAULT_TITLE.to_string());
+        let template = template
+            .clone()
+            .map(crate::unescape_template)
+            .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+
+        let env = crate::create_template_env(ParseModeOpt::None);
+        env.template_from_str(&title)
+            .with_context(|| format!("Invalid {} title template", service.name()))?;
+        env.template_from_str(&template)
+            .with_context(|| format!("Invalid {} template", service.name()))?;
+
+        Ok(Self {
+            service,
+            title,
+            template,
+            priority,
+            alert_priority,
+            messages,
+            channels: channels.to_vec(),
+        })
+    }
+
+    fn ntfy(config: &NtfyConfig) -> Result<Self> {
+        let auth = match (&config.token, &config.username) {
+            (Some(_), Some(_)) => bail!(
+                "Both token and username set for ntfy topic {}",
+                config.topic
+            ),
+            (Some(token), None) => NtfyAuth::Token(token.clone()),
+            (None, Some(username)) => NtfyAuth::Basic(
+                username.clone(),
+                config.password.clone().unwrap_or_default(),
+            ),
+            (None, None) => NtfyAuth::None,
+        };
+        let target = format!("ntfy topic {}", config.topic);
+
+        Self::new(
+            Service::Ntfy {
+                server: config
+                    .server
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_NTFY_SERVER.to_string()),
+                topic: config.topic.clone(),
+                auth,
+                tags: config.tags.clone(),
+            },
+            &config.title,
+            &config.template,
+            priority(config.priority, NTFY_PRIORITY, NTFY_PRIORITIES, &target)?,
+            priority(
+                config.alert_priority,
+                NTFY_ALERT_PRIORITY,
+                NTFY_PRIORITIES,
+                &target,
+            )?,
+            config.messages,
+            &config.channels,
+        )
+    }
+
+    fn gotify(config: &GotifyConfig) -> Result<Self> {
+        let target = format!("Gotify server {}", config.server);
+
+        Self::new(
+            Service::Gotify {
+                url: format!("{}/message", config.server.trim_end_matches('/')),
+                token: config.token.clone(),
+            },
+            &config.title,
+            &config.template,
+            priority(config.priority, GOTIFY_PRIORITY, GOTIFY_PRIORITIES, &target)?,
+            priority(
+                config.alert_priority,
+                GOTIFY_ALERT_PRIORITY,
+                GOTIFY_PRIORITIES,
+                &target,
+            )?,
+            config.messages,
+            &config.channels,
+        )
+    }
+
+    fn render(
+        &self,
+        data: &MessageData,
+        alert: Option<&str>,
+    ) -> Result<(String, String), minijinja::Error> {
+        let ctx = context! {
+            alert => alert,
+            from => data.from.clone(),
+            is_name_conflict => data.is_name_conflict,
+            via => data.via.clone(),
+            text => data.text.clone(),
+            snr => data.snr,
+            rssi => data.rssi,
+            hops_away => data.hops_away,
+            channel => data.channel,
+        };
+        let env = crate::create_template_env(ParseModeOpt::None);
+        Ok((
+            env.render_str(&self.title, &ctx)?,
+            env.render_str(&self.template, &ctx)?,
+        ))
+    }
+}
+
+impl PushSink {
+    pub fn compile(
+        ntfy: &[NtfyConfig],
+        gotify: &[GotifyConfig],
+        policy: RetryPolicy,
+    ) -> Result<Self> {
+        let routes = ntfy
+            .iter()
+            .map(PushRoute::ntfy)
+            .chain(gotify.iter().map(PushRoute::gotify))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { routes, policy })
+    }
+
+    pub fn route_count(&self) -> usize {
+        self.routes.len()
+    }
+
+    // Messages that triggered an alert rule are pushed with the route's alert priority
+    pub async fn deliver(&self, client: &Client, data: &MessageData, alert: Option<&str>) {
+        for route in self
+            .routes
+            .iter()
+            .filter(|r| route_matches(r.messages, &r.channels, data))
+        {
+            let (title, message) = match route.render(data, alert) {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("{}", fl!("failed-to-render", error = e.to_string()));
+                    continue;
+                }
+            };
+            let priority = if alert.is_some() {
+                route.alert_priority
+            } else {
+                route.priority
+            };
+            let service = route.service.name();
+
+            match send_with_retry(&self.policy, || {
+                route.service.request(client, &title, &message, priority)
+            })
+            .await
+            {
+                Ok(_) => {
+                    metrics::counter!("emtt_sink_sends_total", "sink" => service, "result" => "success")
+                        .increment(1);
+                    debug!(
+                        "{}",
+                        fl!(
+                            "forwarded-to-push",
+                            service = service,
+                            from = data.from.clone(),
+                            message = message.clone()
+                        )
+                    );
+                }
+                Err(err) => {
+                    metrics::counter!("emtt_sink_sends_total", "sink" => service, "result" => "failure")
+                        .increment(1);
+                    warn!(
+                        "{}\n{}",
+                        fl!(
+                            "failed-to-send-push",
+                            service = service,
+                            error = err.to_string()
+                        ),
+                        fl!("message-content", content = message.clone())
+                    );
+                }
+            }
+        }
+    }
+}
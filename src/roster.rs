@@ -0,0 +1,533 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result, bail};
+use log::{debug, info, warn};
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use teloxide::Bot;
+use tokio::sync::Notify;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+use crate::events::NodeInfoEvent;
+use crate::monitoring::Health;
+use crate::nodes::{InfoChange, Node, NodeInfo, NodeStore};
+use crate::{ParseModeOpt, fl, telegram};
+
+const DEFAULT_NEW_NODE_TEMPLATE: &str = "👋 New node on the mesh: {{ longname }}, short name {{ shortname }}, ID {{ node_id }}, heard via {{ via }}";
+const DEFAULT_RENAME_TEMPLATE: &str = "✏️ Node {{ node_id }} is now {{ longname }} / {{ shortname }}, was {{ old_longname }} / {{ old_shortname }}";
+const DEFAULT_CONFLICT_TEMPLATE: &str = "⚠️ Node {{ node_id }} calls itself {{ longname }} / {{ shortname }}, the same as {{ other_node_id }} {{ other_longname }} / {{ other_shortname }}";
+// How long messages from a renamed node stay flagged
+const DEFAULT_RENAME_WINDOW_HOURS: u64 = 24;
+
+// Node infos come in bursts after a restart, the state file is written once they settle
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+// The `[nodes]` section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RosterConfig {
+    state_file: Option<PathBuf>,
+    notify_new: bool,
+    notify_rename: bool,
+    notify_conflict: bool,
+    rename_window_hours: Option<u64>,
+    chat_ids: Vec<i64>,
+    new_node_template: Option<String>,
+    rename_template: Option<String>,
+    conflict_template: Option<String>,
+}
+
+// A node as it's kept in the state file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RosterEntry {
+    longname: String,
+    shortname: String,
+    first_seen: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    renamed_at: Option<u64>,
+}
+
+#[derive(Clone)]
+struct Notifications {
+    new_node: bool,
+    rename: bool,
+    conflict: bool,
+    chat_ids: Vec<i64>,
+    new_node_template: String,
+    rename_template: String,
+    conflict_template: String,
+    parse_mode: ParseModeOpt,
+}
+
+// Watches the names nodes announce in the node store, kept across restarts when there's a state file
+#[derive(Clone)]
+pub struct NodeRoster {
+    nodes: NodeStore,
+    path: Option<PathBuf>,
+    loaded: usize,
+    rename_window: u64,
+    notifications: Option<Notifications>,
+    // Set when the state file is behind the node store
+    dirty: Arc<AtomicBool>,
+    changed: Arc<Notify>,
+}
+
+// Writes the state file off the async runtime, runs as a subsystem
+pub struct RosterWriter {
+    nodes: NodeStore,
+    path: PathBuf,
+    dirty: Arc<AtomicBool>,
+    changed: Arc<Notify>,
+}
+
+// The file is keyed by node IDs the way Meshtastic shows them, like "!aabbccdd"
+fn load(path: &Path) -> Result<HashMap<u32, Node>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let entries: BTreeMap<String, RosterEntry> = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    entries
+        .into_iter()
+        .map(|(id, entry)| {
+            let node_id = u32::from_str_radix(id.trim_start_matches('!'), 16)
+                .with_context(|| format!("Invalid node ID {} in {}", id, path.display()))?;
+            let node = Node {
+                info: Some(NodeInfo {
+                    shortname: entry.shortname,
+                    longname: entry.longname,
+                }),
+                first_seen: Some(entry.first_seen),
+                renamed_at: entry.renamed_at,
+                ..Default::default()
+            };
+            Ok((node_id, node))
+        })
+        .collect()
+}
+
+// Written next to the target and renamed, so a crash never leaves half a file
+fn save(path: &Path, nodes: &[(u32, Node)]) -> Result<()> {
+    let entries: BTreeMap<String, RosterEntry> = nodes
+        .iter()
+        .filter_map(|(id, node)| {
+            let info = node.info.as_ref()?;
+            let entry = RosterEntry {
+                longname: info.longname.clone(),
+                shortname: info.shortname.clone(),
+                first_seen: node.first_seen.unwrap_or_default(),
+                renamed_at: node.renamed_at,
+            };
+            Some((format!("!{:08x}", id), entry))
+        })
+        .collect();
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    std::fs::write(&tmp_path, serde_json::to_vec_pretty(&entries)?)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+impl NodeRoster {
+    pub fn new(
+        config: &RosterConfig,
+        default_chat_ids: &[i64],
+        parse_mode: ParseModeOpt,
+    ) -> Result<(Self, Option<RosterWriter>)> {
+        if config.notify_new && config.state_file.is_none() {
+            bail!("notify_new needs a state_file, otherwise every node is new after a restart");
+        }
+
+        let nodes = match &config.state_file {
+            Some(path) => load(path)?,
+            None => HashMap::new(),
+        };
+        let loaded = nodes.len();
+        let nodes = NodeStore::new(nodes);
+
+        let notifications = if config.notify_new || config.notify_rename || config.notify_conflict {
+            let chat_ids = if config.chat_ids.is_empty() {
+                default_chat_ids.to_vec()
+            } else {
+                config.chat_ids.clone()
+            };
+            if chat_ids.is_empty() {
+                bail!("No chat IDs for node notifications");
+            }
+
+            let new_node_template = config
+                .new_node_template
+                .clone()
+                .map(crate::unescape_template)
+                .unwrap_or_else(|| DEFAULT_NEW_NODE_TEMPLATE.to_string());
+            let rename_template = config
+                .rename_template
+                .clone()
+                .map(crate::unescape_template)
+                .unwrap_or_else(|| DEFAULT_RENAME_TEMPLATE.to_string());
+            let conflict_template = config
+                .conflict_template
+                .clone()
+                .map(crate::unescape_template)
+                .unwrap_or_else(|| DEFAULT_CONFLICT_TEMPLATE.to_string());
+            let env = crate::create_template_env(parse_mode);
+            env.template_from_str(&new_node_template)
+                .context("Invalid new node template")?;
+            env.template_from_str(&rename_template)
+                .context("Invalid rename template")?;
+            env.template_from_str(&conflict_template)
+                .context("Invalid conflict template")?;
+
+            Some(Notifications {
+                new_node: config.notify_new,
+                rename: config.notify_rename,
+                conflict: config.notify_conflict,
+                chat_ids,
+                new_node_template,
+                rename_template,
+                conflict_template,
+                parse_mode,
+            })
+        } else {
+            None
+        };
+
+        let dirty = Arc::new(AtomicBool::new(false));
+        let changed = Arc::new(Notify::new());
+        let writer = config.state_file.as_ref().map(|path| RosterWriter {
+            nodes: nodes.clone(),
+            path: path.clone(),
+            dirty: dirty.clone(),
+            changed: changed.clone(),
+        });
+
+        Ok((
+            Self {
+                nodes,
+                path: config.state_file.clone(),
+                loaded,
+                rename_window: config
+                    .rename_window_hours
+                    .unwrap_or(DEFAULT_RENAME_WINDOW_HOURS)
+                    * 3600,
+                notifications,
+                dirty,
+                changed,
+            },
+            writer,
+        ))
+    }
+
+    // The store the roster keeps names in, shared with the syslog server and the bot
+    pub fn nodes(&self) -> NodeStore {
+        self.nodes.clone()
+    }
+
+    // Nodes loaded from the state file at startup
+    pub fn node_count(&self) -> usize {
+        self.loaded
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn needs_bot(&self) -> bool {
+        self.notifications.is_some()
+    }
+
+    // Another node announcing the same name, or a recent rename, is how someone
+    // would pass themselves off as a trusted person
+    pub async fn is_name_conflict(&self, node_id: u32, now: u64) -> bool {
+        let Some(node) = self.nodes.get(node_id).await else {
+            return false;
+        };
+        if node
+            .renamed_at
+            .is_some_and(|at| now.saturating_sub(at) < self.rename_window)
+        {
+            return true;
+        }
+        self.nodes.same_name(node_id).await.is_some()
+    }
+
+    fn render(
+        notifications: &Notifications,
+        info: &NodeInfoEvent,
+        change: &InfoChange,
+    ) -> Result<String, minijinja::Error> {
+        let env = crate::create_template_env(notifications.parse_mode);
+        let node_id = format!("!{:08x}", info.node_id);
+        match change {
+            InfoChange::New => env.render_str(
+                &notifications.new_node_template,
+                context! {
+                    node_id => node_id,
+                    longname => info.longname.clone(),
+                    shortname => info.shortname.clone(),
+                    via => info.via.clone(),
+                },
+            ),
+            InfoChange::Renamed(previous) => env.render_str(
+                &notifications.rename_template,
+                context! {
+                    node_id => node_id,
+                    longname => info.longname.clone(),
+                    shortname => info.shortname.clone(),
+                    old_longname => previous.longname.clone(),
+                    old_shortname => previous.shortname.clone(),
+                    via => info.via.clone(),
+                },
+            ),
+        }
+    }
+
+    fn render_conflict(
+        notifications: &Notifications,
+        info: &NodeInfoEvent,
+        other_id: u32,
+        other: &NodeInfo,
+    ) -> Result<String, minijinja::Error> {
+        let env = crate::create_template_env(notifications.parse_mode);
+        env.render_str(
+            &notifications.conflict_template,
+            context! {
+                node_id => format!("!{:08x}", info.node_id),
+                longname => info.longname.clone(),
+                shortname => info.shortname.clone(),
+                other_node_id => format!("!{:08x}", other_id),
+                other_longname => other.longname.clone(),
+                other_shortname => other.shortname.clone(),
+                via => info.via.clone(),
+            },
+        )
+    }
+
+    pub async fn observe(&self, info: &NodeInfoEvent, bot: Option<&Bot>, health: &Health) {
+        let announced = NodeInfo {
+            shortname: info.shortname.clone(),
+            longname: info.longname.clone(),
+        };
+        let Some(change) = self
+            .nodes
+            .update_info(info.node_id, announced, info.received_at)
+            .await
+        else {
+            return;
+        };
+        if self.path.is_some() {
+            self.dirty.store(true, Ordering::Relaxed);
+            self.changed.notify_one();
+        }
+
+        let node_id = format!("!{:08x}", info.node_id);
+        let notify = match &change {
+            InfoChange::New => {
+                info!(
+                    "{}",
+                    fl!(
+                        "node-discovered",
+                        id = node_id.clone(),
+                        longname = info.longname.clone()
+                    )
+                );
+                self.notifications.as_ref().filter(|n| n.new_node)
+            }
+            InfoChange::Renamed(previous) => {
+                info!(
+                    "{}",
+                    fl!(
+                        "node-renamed",
+                        id = node_id.clone(),
+                        old = previous.longname.clone(),
+                        new = info.longname.clone()
+                    )
+                );
+                self.notifications.as_ref().filter(|n| n.rename)
+            }
+        };
+        if let (Some(notifications), Some(bot)) = (notify, bot) {
+            match Self::render(notifications, info, &change) {
+                Ok(rendered) => Self::send(bot, notifications, &node_id, rendered, health),
+                Err(e) => warn!("{}", fl!("failed-to-render", error = e.to_string())),
+            }
+        }
+
+        let Some((other_id, other)) = self.nodes.same_name(info.node_id).await else {
+            return;
+        };
+        warn!(
+            "{}",
+            fl!(
+                "node-name-conflict",
+                id = node_id.clone(),
+                other = format!("!{:08x}", other_id),
+                longname = info.longname.clone()
+            )
+        );
+        let (Some(notifications), Some(bot)) =
+            (self.notifications.as_ref().filter(|n| n.conflict), bot)
+        else {
+            return;
+        };
+        match Self::render_conflict(notifications, info, other_id, &other) {
+            Ok(rendered) => Self::send(bot, notifications, &node_id, rendered, health),
+            Err(e) => warn!("{}", fl!("failed-to-render", error = e.to_string())),
+        }
+    }
+
+    // Sent in the background so a slow Telegram doesn't hold up the syslog server
+    fn send(
+        bot: &Bot,
+        notifications: &Notifications,
+        node_id: &str,
+        rendered: String,
+        health: &Health,
+    ) {
+        let bot = bot.clone();
+        let chat_ids = notifications.chat_ids.clone();
+        let parse_mode = notifications.parse_mode;
+        let node_id = node_id.to_string();
+        let sending = health.outbox_entry();
+        tokio::spawn(async move {
+            let _sending = sending;
+            for chat_id in chat_ids {
+                match telegram::send_message(
+                    &bot,
+                    chat_id,
+                    &rendered,
+                    parse_mode.telegram(),
+                    false,
+                    None,
+                    None,
+                )
+                .await
+                {
+                    Ok(_) => debug!(
+                        "{}",
+                        fl!(
+                            "node-notification-sent",
+                            id = node_id.clone(),
+                            chat_id = chat_id.to_string()
+                        )
+                    ),
+                    Err(err) => warn!(
+                        "{}\n{}",
+                        fl!("failed-to-send", error = err.to_string()),
+                        fl!("message-content", content = rendered.clone())
+                    ),
+                }
+            }
+        });
+    }
+}
+
+impl RosterWriter {
+    async fn save(&self) {
+        self.dirty.store(false, Ordering::Relaxed);
+        let nodes = self.nodes.named().await;
+        let path = self.path.clone();
+        let result = tokio::task::spawn_blocking(move || save(&path, &nodes))
+            .await
+            .context("Roster writer thread failed")
+            .and_then(|saved| saved);
+        if let Err(e) = result {
+            warn!(
+                "{}",
+                fl!("failed-to-save-roster", error = format!("{:#}", e))
+            );
+        }
+    }
+
+    pub async fn run(self, subsys: SubsystemHandle) -> Result<()> {
+        loop {
+            tokio::select! {
+                _ = self.changed.notified() => {}
+                _ = subsys.on_shutdown_requested() => break,
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(SAVE_DELAY) => {}
+                _ = subsys.on_shutdown_requested() => break,
+            }
+            self.save().await;
+        }
+
+        // Changes since the last save would be lost otherwise
+        if self.dirty.load(Ordering::Relaxed) {
+            self.save().await;
+        }
+        debug!("{}", fl!("roster-shutdown"));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roster() -> NodeRoster {
+        let config = RosterConfig {
+            rename_window_hours: Some(1),
+            ..Default::default()
+        };
+        let (roster, writer) = NodeRoster::new(&config, &[], ParseModeOpt::None).unwrap();
+        assert!(writer.is_none());
+        roster
+    }
+
+    async fn announce(roster: &NodeRoster, node_id: u32, longname: &str, at: u64) {
+        let info = NodeInfoEvent {
+            id: None,
+            node_id,
+            shortname: longname[..2].to_uppercase(),
+            longname: longname.to_string(),
+            via: "gw1".to_string(),
+            received_at: at,
+        };
+        roster.observe(&info, None, &Health::new(false)).await;
+    }
+
+    #[tokio::test]
+    async fn flags_nodes_sharing_a_name() {
+        let roster = roster();
+        announce(&roster, 1, "Alice", 1000).await;
+        announce(&roster, 2, "Bob", 1000).await;
+        assert!(!roster.is_name_conflict(1, 1000).await);
+        assert!(!roster.is_name_conflict(2, 1000).await);
+        // Nodes the roster hasn't heard announce themselves
+        assert!(!roster.is_name_conflict(3, 1000).await);
+
+        announce(&roster, 3, " alice", 1000).await;
+        assert!(roster.is_name_conflict(3, 1000).await);
+        assert!(roster.is_name_conflict(1, 1000).await);
+        assert!(!roster.is_name_conflict(2, 1000).await);
+    }
+
+    #[tokio::test]
+    async fn flags_recent_renames() {
+        let roster = roster();
+        announce(&roster, 1, "Alice", 1000).await;
+        announce(&roster, 1, "Mallory", 2000).await;
+        assert!(roster.is_name_conflict(1, 2000).await);
+        assert!(roster.is_name_conflict(1, 2000 + 3599).await);
+        assert!(!roster.is_name_conflict(1, 2000 + 3600).await);
+    }
+
+    #[tokio::test]
+    async fn renaming_itself_is_not_a_conflict() {
+        let roster = roster();
+        announce(&roster, 1, "Alice", 1000).await;
+        announce(&roster, 1, "ALICE ", 2000).await;
+        assert!(!roster.is_name_conflict(1, 2000).await);
+    }
+}
@@ -5,7 +5,7 @@
 use anyhow::{Result, Context};
 use log::{debug, info, trace, warn};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -16,11 +16,19 @@ use tokio_graceful_shutdown::{SubsystemHandle, SubsystemBuilder};
 
 use crate::Config;
 use crate::MessageData;
+use crate::events::{Event, IgnoreReason, IgnoredEvent, NodeInfoEvent, TelemetryEvent};
 use crate::fl;
 use crate::lang;
+use crate::monitoring::Health;
+use crate::nodes::{NodeStore, Reception};
 
 use once_cell::sync::Lazy;
 
+pub const BROADCAST_ADDR: u32 = 0xffffffff;
+
+const NODEINFO_PORTNUM: u32 = 4;
+const TELEMETRY_PORTNUM: u32 = 67;
+
 static NODEINFO_RE: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"Update changed=\d+ user (.+)/([^,/]+), id=0x([0-9a-fA-F]+), channel=\d+").unwrap()
 });
@@ -33,16 +41,15 @@ static TEXT_MSG_RE: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"(?s)Received text msg from=0x([0-9a-fA-F]+), id=0x([0-9a-fA-F]+), msg=(.+)").unwrap()
 });
 
+// "(Received from SHORT): air_util_tx=..., ..." for received telemetry, "Send: ..." for the gateway's own
+static TELEMETRY_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:\(Received from ([^)]*)\)|Send): (\w+=\S+(?:, \w+=\S+)*)$").unwrap()
+});
+
 static RANGE_TEST_RE: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"^seq \d+$").unwrap()
 });
 
-#[derive(Clone)]
-struct NodeInfo {
-    shortname: String,
-    longname: String,
-}
-
 #[derive(Clone)]
 struct ViaInfo {
     to: u32,
@@ -51,8 +58,10 @@ struct ViaInfo {
     rssi: Option<i32>,
     hop_lim: Option<u32>,
     hop_start: Option<u32>,
-    fr: Option<u32>,
+    reply_id: Option<u32>,
+    is_emoji: bool,
     is_mqtt: bool,
+    rx_time: Option<u64>,
     timestamp: u64,
 }
 
@@ -60,6 +69,22 @@ struct HandleInfo {
     vias: HashMap<String, ViaInfo>,
 }
 
+// The last packet a gateway logged, to match the lines that follow it
+struct LastPacket {
+    id: u32,
+    from: u32,
+    portnum: u32,
+}
+
+type LastPackets = HashMap<String, LastPacket>;
+
+fn take_last_packet(last_packets: &mut LastPackets, ident: &str, portnum: u32) -> Option<LastPacket> {
+    if last_packets.get(ident)?.portnum != portnum {
+        return None;
+    }
+    last_packets.remove(ident)
+}
+
 fn now() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -67,7 +92,42 @@ fn now() -> u64 {
         .as_secs()
 }
 
-fn parse_syslog_message(text: &str) -> Result<(String, String), &'static str> {
+fn count_hops(hop_start: Option<u32>, hop_lim: Option<u32>) -> Option<i32> {
+    hop_start
+        .zip(hop_lim)
+        .map(|(hs, hl)| hs.saturating_sub(hl) as i32)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ParseError {
+    InvalidPri,
+    MissingTimestamp,
+    MissingSeparator,
+    MissingIdent,
+}
+
+impl ParseError {
+    // Label for the parse failure counter
+    fn name(self) -> &'static str {
+        match self {
+            ParseError::InvalidPri => "invalid_pri",
+            ParseError::MissingTimestamp => "missing_timestamp",
+            ParseError::MissingSeparator => "missing_separator",
+            ParseError::MissingIdent => "missing_ident",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            ParseError::InvalidPri => "Invalid PRI/version format",
+            ParseError::MissingTimestamp => "Missing timestamp",
+            ParseError::MissingSeparator => "Missing message separator",
+            ParseError::MissingIdent => "Missing ident",
+        }
+    }
+}
+
+fn parse_syslog_message(text: &str) -> Result<(String, String), ParseError> {
     let mut cursor: usize = 0;
 
     // Skip PRI and version if present
@@ -75,7 +135,7 @@ fn parse_syslog_message(text: &str) -> Result<(String, String), &'static str> {
         if let Some(pri_end) = text.find(' ') {
             cursor = pri_end + 1;
         } else {
-            return Err("Invalid PRI/version format");
+            return Err(ParseError::InvalidPri);
         }
     }
 
@@ -83,7 +143,7 @@ fn parse_syslog_message(text: &str) -> Result<(String, String), &'static str> {
     if let Some(ts_end) = text[cursor..].find(' ').map(|i| i + cursor) {
         cursor = ts_end + 1;
     } else {
-        return Err("Missing timestamp");
+        return Err(ParseError::MissingTimestamp);
     }
 
     // Parse ident
@@ -99,19 +159,26 @@ fn parse_syslog_message(text: &str) -> Result<(String, String), &'static str> {
             }
 
             let message = text[cursor..].trim_end_matches('\n').to_string();
-            return Ok((ident, message));
+            Ok((ident, message))
         } else {
-            return Err("Missing message separator");
+            Err(ParseError::MissingSeparator)
         }
     } else {
-        return Err("Missing ident");
+        Err(ParseError::MissingIdent)
     }
 }
 
-async fn parse_and_store_nodeinfo(
+// The names go to the node store through the roster, which watches them for changes
+async fn parse_and_process_nodeinfo<F, Fut>(
     message: &str,
-    known_nodes: &Arc<Mutex<HashMap<u32, NodeInfo>>>,
-) -> bool {
+    ident: &str,
+    sender: &F,
+    last_packets: &mut LastPackets,
+) -> bool
+where
+    F: Fn(Event) -> Fut,
+    Fut: Future<Output = ()>,
+{
     if let Some(caps) = NODEINFO_RE.captures(message) {
         let longname = caps[1].to_string();
         let shortname = caps[2].to_string();
@@ -120,16 +187,18 @@ async fn parse_and_store_nodeinfo(
             Err(_) => return false,
         };
 
-        let mut nodes = known_nodes.lock().await;
-        nodes.insert(
-            id,
-            NodeInfo {
-                shortname: shortname.clone(),
-                longname: longname.clone(),
-            },
-        );
+        debug!("{}", fl!("processed-nodeinfo", longname = longname.as_str(), shortname = shortname.as_str(), id = format!("0x{:08x}", id)));
 
-        debug!("{}", fl!("processed-nodeinfo", longname = longname, shortname = shortname, id = format!("0x{:08x}", id)));
+        let packet = take_last_packet(last_packets, ident, NODEINFO_PORTNUM).filter(|p| p.from == id);
+        sender(Event::NodeInfo(NodeInfoEvent {
+            id: packet.map(|p| p.id),
+            node_id: id,
+            shortname,
+            longname,
+            via: ident.to_string(),
+            received_at: now(),
+        }))
+        .await;
         return true;
     }
 
@@ -140,6 +209,8 @@ async fn parse_and_store_handle_received(
     message: &str,
     ident: &str,
     handle_infos: &Arc<Mutex<HashMap<u32, HandleInfo>>>,
+    known_nodes: &NodeStore,
+    last_packets: &mut LastPackets,
 ) -> bool {
     if let Some(caps) = HANDLE_RECEIVED_RE.captures(message) {
         let mut content = caps[2].to_string();
@@ -155,6 +226,44 @@ async fn parse_and_store_handle_received(
             }
         }
 
+        let snr = fields.get("rxSNR").and_then(|s| s.parse::<f32>().ok());
+        let rssi = fields.get("rxRSSI").and_then(|s| s.parse::<i32>().ok());
+        let hop_lim = fields.get("HopLim").and_then(|s| s.parse::<u32>().ok());
+        let hop_start = fields.get("hopStart").and_then(|s| s.parse::<u32>().ok());
+        let via_str = fields.get("via").cloned().unwrap_or_default();
+
+        let is_mqtt = via_str == "MQTT";
+
+        // Every packet heard over the radio goes into the sender's signal history
+        let fr = fields
+            .get("fr")
+            .and_then(|s| u32::from_str_radix(s.trim_start_matches("0x"), 16).ok());
+        if let Some(fr) = fr
+            && !is_mqtt
+            && snr.is_some()
+        {
+            known_nodes
+                .record_reception(
+                    fr,
+                    Reception {
+                        timestamp: now(),
+                        via: ident.to_string(),
+                        snr,
+                        rssi,
+                        hops_away: count_hops(hop_start, hop_lim),
+                    },
+                )
+                .await;
+        }
+
+        let portnum = fields.get("Portnum").and_then(|s| s.parse::<u32>().ok());
+        let packet_id = fields
+            .get("id")
+            .and_then(|s| u32::from_str_radix(s.trim_start_matches("0x"), 16).ok());
+        if let (Some(id), Some(from), Some(portnum)) = (packet_id, fr, portnum) {
+            last_packets.insert(ident.to_string(), LastPacket { id, from, portnum });
+        }
+
         if fields.get("Portnum").map(|s| s.as_str()) != Some("1") {
             return true; // Not text, but handled
         }
@@ -169,9 +278,6 @@ async fn parse_and_store_handle_received(
             Err(_) => return false,
         };
 
-        let fr = fields
-            .get("fr")
-            .and_then(|s| u32::from_str_radix(&s[2..], 16).ok());
         let to = fields
             .get("to")
             .and_then(|s| u32::from_str_radix(&s[2..], 16).ok());
@@ -179,14 +285,17 @@ async fn parse_and_store_handle_received(
             .get("Ch")
             .and_then(|s| u32::from_str_radix(&s[2..], 16).ok())
             .unwrap_or(0);
-        let snr = fields.get("rxSNR").and_then(|s| s.parse::<f32>().ok());
-        let rssi = fields.get("rxRSSI").and_then(|s| s.parse::<i32>().ok());
-        let hop_lim = fields.get("HopLim").and_then(|s| s.parse::<u32>().ok());
-        let hop_start = fields.get("hopStart").and_then(|s| s.parse::<u32>().ok());
-        let via_str = fields.get("via").cloned().unwrap_or_default();
-
-        let is_mqtt = via_str == "MQTT";
-
+        let reply_id = fields.get("replyId").and_then(|s| {
+            u32::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+        });
+        let is_emoji = fields
+            .get("emoji")
+            .is_some_and(|s| s != "0");
+        // Gateways without a clock report rxtime=0
+        let rx_time = fields
+            .get("rxtime")
+            .and_then(|s| s.parse::<u64>().ok())
+            .filter(|t| *t != 0);
         let mut handles = handle_infos.lock().await;
         let entry = handles.entry(id).or_insert(HandleInfo {
             vias: HashMap::new(),
@@ -201,11 +310,14 @@ async fn parse_and_store_handle_received(
                 rssi,
                 hop_lim,
                 hop_start,
-                fr,
+                reply_id,
+                is_emoji,
                 is_mqtt,
+                rx_time,
                 timestamp: now(),
             },
         );
+        metrics::gauge!("emtt_handle_infos").set(handles.len() as f64);
 
         debug!("{}", fl!("stored-handle-info",
             id = format!("0x{:08x}", id),
@@ -220,16 +332,62 @@ async fn parse_and_store_handle_received(
     false
 }
 
+// The sender name, None for the gateway's own telemetry, and the logged values
+pub fn parse_telemetry(message: &str) -> Option<(Option<String>, BTreeMap<String, f64>)> {
+    let caps = TELEMETRY_RE.captures(message)?;
+
+    let mut metrics = BTreeMap::new();
+    for pair in caps[2].split(", ") {
+        let (key, value) = pair.split_once('=')?;
+        metrics.insert(key.to_string(), value.parse::<f64>().ok()?);
+    }
+
+    Some((caps.get(1).map(|m| m.as_str().to_string()), metrics))
+}
+
+async fn parse_and_process_telemetry<F, Fut>(
+    message: &str,
+    ident: &str,
+    sender: &F,
+    last_packets: &mut LastPackets,
+) -> bool
+where
+    F: Fn(Event) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let Some((sender_name, metrics)) = parse_telemetry(message) else {
+        return false;
+    };
+
+    let packet = match sender_name {
+        Some(_) => take_last_packet(last_packets, ident, TELEMETRY_PORTNUM),
+        None => None,
+    };
+
+    debug!("{}", fl!("processed-telemetry", via = ident, metrics = metrics.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(", ")));
+
+    sender(Event::Telemetry(TelemetryEvent {
+        id: packet.as_ref().map(|p| p.id),
+        from_id: packet.map(|p| p.from),
+        sender: sender_name,
+        metrics,
+        via: ident.to_string(),
+        received_at: now(),
+    }))
+    .await;
+    true
+}
+
 async fn parse_and_process_text_message<F, Fut>(
     message: &str,
     ident: &str,
     config: &Config,
     sender: &F,
     handle_infos: &Arc<Mutex<HashMap<u32, HandleInfo>>>,
-    known_nodes: &Arc<Mutex<HashMap<u32, NodeInfo>>>,
+    known_nodes: &NodeStore,
 ) -> bool
 where
-    F: Fn(MessageData) -> Fut,
+    F: Fn(Event) -> Fut,
     Fut: Future<Output = ()>,
 {
     if let Some(caps) = TEXT_MSG_RE.captures(message) {
@@ -248,37 +406,58 @@ where
         let formatted_id = format!("0x{:08x}", id);
 
         info!("{}", fl!("received-text-msg", from = from_hex.as_str(), id = formatted_id.as_str(), text = text.as_str()));
+        metrics::counter!("emtt_text_messages_total").increment(1);
+
+        // Messages that aren't forwarded still go to the archive
+        let ignored = |reason: IgnoreReason, via_info: Option<&ViaInfo>| {
+            metrics::counter!("emtt_text_messages_ignored_total", "reason" => reason.name())
+                .increment(1);
+            Event::Ignored(IgnoredEvent {
+                id,
+                from_id: from,
+                text: text.clone(),
+                reason,
+                to: via_info.map(|v| v.to),
+                channel: via_info.map(|v| v.ch),
+                via: ident.to_string(),
+                received_at: now(),
+            })
+        };
 
         if RANGE_TEST_RE.is_match(&text) {
             debug!("{}", fl!("ignoring-range-test", from = from_hex, id = format!("0x{:08x}", id)));
+            sender(ignored(IgnoreReason::RangeTest, None)).await;
             return true;
         }
 
         let handles = handle_infos.lock().await;
-        let h = match handles.get(&id) {
-            Some(h) => h,
+        let via_info = match handles.get(&id) {
+            Some(h) => match h.vias.get(ident) {
+                Some(v) => Some(v.clone()),
+                None => {
+                    warn!("{}", fl!("no-via-info", id = format!("0x{:08x}", id), via = ident));
+                    None
+                }
+            },
             None => {
                 warn!("{}", fl!("no-handle-info", id = format!("0x{:08x}", id)));
-                return true;
+                None
             }
         };
+        drop(handles); // release lock early
 
-        let via_key = ident.to_string();
-        let via_info = match h.vias.get(&via_key) {
-            Some(v) => v.clone(),
-            None => {
-                warn!("{}", fl!("no-via-info", id = format!("0x{:08x}", id), via = ident));
-                return true;
-            }
+        let Some(via_info) = via_info else {
+            sender(ignored(IgnoreReason::NoPacketInfo, None)).await;
+            return true;
         };
-        drop(handles); // release lock early
 
         if via_info.is_mqtt {
             debug!("{}", fl!("skipping-mqtt", id = format!("0x{:08x}", id)));
+            sender(ignored(IgnoreReason::Mqtt, Some(&via_info))).await;
             return true;
         }
 
-        let forward = if via_info.to == 0xffffffff {
+        let forward = if via_info.to == BROADCAST_ADDR {
             if let Some(ch) = config.channel {
                 via_info.ch == ch
             } else {
@@ -290,19 +469,19 @@ where
 
         if !forward {
             info!("{}", fl!("ignoring-text-msg", id = format!("0x{:08x}", id), ch = via_info.ch, to = format!("0x{:08x}", via_info.to)));
+            sender(ignored(IgnoreReason::Filtered, Some(&via_info))).await;
             return true;
         }
 
         let snr = via_info.snr;
         let rssi = via_info.rssi;
-        let hops_away = via_info.hop_start.zip(via_info.hop_lim).map(|(hs, hl)| hs.saturating_sub(hl) as i32);
+        let hops_away = count_hops(via_info.hop_start, via_info.hop_lim);
 
         // Format and send to Telegram
-        let mut from_name = known_nodes
-            .lock()
-            .await
-            .get(&from)
-            .map(|n| n.longname.clone())
+        let info = known_nodes.get(from).await.and_then(|n| n.info);
+        let mut from_name = info
+            .as_ref()
+            .map(|i| i.longname.clone())
             .unwrap_or(from_hex.clone());
 
         if from == 0 {
@@ -318,9 +497,21 @@ where
             snr,
             rssi,
             hops_away,
+            id,
+            from_id: from,
+            to: via_info.to,
+            reply_id: via_info.reply_id,
+            is_reaction: via_info.is_emoji && via_info.reply_id.is_some(),
+            channel: via_info.ch,
+            shortname: info.as_ref().map(|i| i.shortname.clone()),
+            longname: info.map(|i| i.longname),
+            received_at: via_info.timestamp,
+            rx_time: via_info.rx_time,
+            is_name_conflict: config.roster.is_name_conflict(from, via_info.timestamp).await,
         };
 
-        sender(data).await;
+        metrics::counter!("emtt_text_messages_forwarded_total").increment(1);
+        sender(Event::Text(data)).await;
         return true;
     }
 
@@ -347,6 +538,7 @@ async fn cleanup_subsystem(
                     handles.remove(&id);
                     trace!("{}", fl!("cleaned-stale-handle-info", id = format!("0x{:08x}", id)));
                 }
+                metrics::gauge!("emtt_handle_infos").set(handles.len() as f64);
             }
             _ = subsys.on_shutdown_requested() => {
                 debug!("{}", fl!("cleanup-shutdown"));
@@ -360,13 +552,15 @@ async fn cleanup_subsystem(
 pub async fn run_server<F>(
     subsys: SubsystemHandle,
     config: Config,
+    known_nodes: NodeStore,
+    health: Health,
     sender: F,
 ) -> Result<()>
 where
-    F: Fn(MessageData) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync + 'static,
+    F: Fn(Event) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync + 'static,
 {
-    let known_nodes: Arc<Mutex<HashMap<u32, NodeInfo>>> = Arc::new(Mutex::new(HashMap::new()));
     let handle_infos: Arc<Mutex<HashMap<u32, HandleInfo>>> = Arc::new(Mutex::new(HashMap::new()));
+    let mut last_packets = LastPackets::new();
 
     let addr = format!("{}:{}", config.syslog_host, config.syslog_port);
     let socket = UdpSocket::bind(&addr)
@@ -374,6 +568,7 @@ where
         .with_context(|| format!("Failed to bind UDP socket on {}", addr))?;
 
     info!("{}", fl!("syslog-binding", addr = addr));
+    health.set_syslog_bound();
 
     let handle_infos_clone = handle_infos.clone();
     subsys.start(SubsystemBuilder::new(
@@ -387,9 +582,13 @@ where
             res = socket.recv_from(&mut buf) => {
                 match res {
                     Ok((len, peer)) => {
+                        metrics::counter!("emtt_syslog_datagrams_total").increment(1);
+
                         let msg = match String::from_utf8(buf[..len].to_vec()) {
                             Ok(m) => m,
                             Err(_) => {
+                                metrics::counter!("emtt_syslog_parse_failures_total", "error" => "invalid_utf8")
+                                    .increment(1);
                                 warn!("{}", fl!("invalid-utf8", peer = peer.to_string()));
                                 continue;
                             }
@@ -398,16 +597,26 @@ where
                         let (ident, message) = match parse_syslog_message(&msg) {
                             Ok(r) => r,
                             Err(err) => {
-                                warn!("{}", fl!("failed-to-parse-syslog", error = err, raw = msg));
+                                metrics::counter!("emtt_syslog_parse_failures_total", "error" => err.name())
+                                    .increment(1);
+                                warn!("{}", fl!("failed-to-parse-syslog", error = err.message(), raw = msg));
                                 continue;
                             }
                         };
 
-                        if parse_and_store_nodeinfo(&message, &known_nodes).await {
+                        metrics::gauge!("emtt_gateway_last_packet_timestamp_seconds", "gateway" => ident.clone())
+                            .set(now() as f64);
+                        config.gateways.record(&ident);
+
+                        if parse_and_process_nodeinfo(&message, &ident, &sender, &mut last_packets).await {
                             continue;
                         }
 
-                        if parse_and_store_handle_received(&message, &ident, &handle_infos).await {
+                        if parse_and_store_handle_received(&message, &ident, &handle_infos, &known_nodes, &mut last_packets).await {
+                            continue;
+                        }
+
+                        if parse_and_process_telemetry(&message, &ident, &sender, &mut last_packets).await {
                             continue;
                         }
 
@@ -440,3 +649,50 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_remote_telemetry() {
+        let (sender, metrics) = parse_telemetry(
+            "(Received from Node_abcd): air_util_tx=0.712, channel_utilization=12.5, battery_level=87, voltage=3.98",
+        )
+        .unwrap();
+        assert_eq!(sender.as_deref(), Some("Node_abcd"));
+        assert_eq!(metrics.len(), 4);
+        assert_eq!(metrics["air_util_tx"], 0.712);
+        assert_eq!(metrics["channel_utilization"], 12.5);
+        assert_eq!(metrics["battery_level"], 87.0);
+        assert_eq!(metrics["voltage"], 3.98);
+    }
+
+    #[test]
+    fn parses_local_telemetry() {
+        let (sender, metrics) = parse_telemetry(
+            "Send: air_util_tx=0.05, channel_utilization=3.1, battery_level=101, voltage=4.2, uptime_seconds=3600",
+        )
+        .unwrap();
+        assert_eq!(sender, None);
+        assert_eq!(metrics["battery_level"], 101.0);
+        assert_eq!(metrics["uptime_seconds"], 3600.0);
+    }
+
+    #[test]
+    fn parses_environment_telemetry() {
+        let (_, metrics) = parse_telemetry(
+            "(Received from Weather): barometric_pressure=1013.2, relative_humidity=45.0, temperature=-3.5",
+        )
+        .unwrap();
+        assert_eq!(metrics["temperature"], -3.5);
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert!(parse_telemetry("Send: hello world").is_none());
+        assert!(parse_telemetry("(Received from X): voltage=high").is_none());
+        assert!(parse_telemetry("Received text msg from=0x1, id=0x2, msg=voltage=3").is_none());
+        assert!(parse_telemetry("Send: voltage=3.9,battery_level=50").is_none());
+    }
+}
@@ -2,8 +2,80 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use teloxide::{prelude::*, types::{ChatId, ParseMode}};
+use log::{debug, warn};
+use minijinja::context;
 use reqwest::Client;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use teloxide::{
+    prelude::*,
+    types::{ChatId, InlineKeyboardMarkup, MessageId, ParseMode, ReactionType, ReplyParameters},
+};
+use tokio::sync::Mutex;
+
+use crate::notifications::NotificationRules;
+use crate::{Config, MessageData, ParseModeOpt, bot, fl};
+
+// How many forwarded packets are remembered for edits
+const SENT_CAPACITY: usize = 1000;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GatewayReport {
+    via: String,
+    snr: Option<f32>,
+    rssi: Option<i32>,
+    hops_away: Option<i32>,
+}
+
+impl From<&MessageData> for GatewayReport {
+    fn from(data: &MessageData) -> Self {
+        Self {
+            via: data.via.clone(),
+            snr: data.snr,
+            rssi: data.rssi,
+            hops_away: data.hops_away,
+        }
+    }
+}
+
+struct SentPacket {
+    data: MessageData,
+    gateways: Vec<GatewayReport>,
+    messages: Vec<(i64, MessageId)>,
+}
+
+#[derive(Default)]
+struct SentStore {
+    packets: HashMap<u32, SentPacket>,
+    order: VecDeque<u32>,
+}
+
+impl SentStore {
+    fn insert(&mut self, id: u32, packet: SentPacket) {
+        if self.packets.insert(id, packet).is_none() {
+            self.order.push_back(id);
+        }
+        while self.order.len() > SENT_CAPACITY {
+            if let Some(old) = self.order.pop_front() {
+                self.packets.remove(&old);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TelegramSink {
+    bot: Bot,
+    chat_ids: Vec<i64>,
+    template: String,
+    parse_mode: ParseModeOpt,
+    notifications: NotificationRules,
+    edit_duplicates: bool,
+    reply_threading: bool,
+    inline_keyboard: bool,
+    sent: Arc<Mutex<SentStore>>,
+}
 
 pub fn init_bot(token: String, client: Client) -> Bot {
     // https://github.com/teloxide/teloxide/issues/223
@@ -15,11 +87,351 @@ pub async fn send_message(
     chat_id: i64,
     message: &str,
     parse_mode: Option<ParseMode>,
-) -> Result<(), teloxide::RequestError> {
+    disable_notification: bool,
+    reply_to: Option<MessageId>,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> Result<MessageId, teloxide::RequestError> {
     let mut req = bot.send_message(ChatId(chat_id), message);
     if let Some(pm) = parse_mode {
         req = req.parse_mode(pm);
     }
+    if disable_notification {
+        req = req.disable_notification(true);
+    }
+    if let Some(message_id) = reply_to {
+        req = req.reply_parameters(ReplyParameters::new(message_id).allow_sending_without_reply());
+    }
+    if let Some(markup) = keyboard {
+        req = req.reply_markup(markup);
+    }
+    // Counted here so that alerts and notices from other subsystems show up too
+    match req.await {
+        Ok(sent) => {
+            metrics::counter!("emtt_sink_sends_total", "sink" => "telegram", "result" => "success")
+                .increment(1);
+            Ok(sent.id)
+        }
+        Err(err) => {
+            metrics::counter!("emtt_sink_sends_total", "sink" => "telegram", "result" => "failure")
+                .increment(1);
+            Err(err)
+        }
+    }
+}
+
+pub async fn edit_message(
+    bot: &Bot,
+    chat_id: i64,
+    message_id: MessageId,
+    message: &str,
+    parse_mode: Option<ParseMode>,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> Result<(), teloxide::RequestError> {
+    let mut req = bot.edit_message_text(ChatId(chat_id), message_id, message);
+    if let Some(pm) = parse_mode {
+        req = req.parse_mode(pm);
+    }
+    if let Some(markup) = keyboard {
+        req = req.reply_markup(markup);
+    }
     req.await?;
     Ok(())
 }
+
+pub async fn set_reaction(
+    bot: &Bot,
+    chat_id: i64,
+    message_id: MessageId,
+    emoji: &str,
+) -> Result<(), teloxide::RequestError> {
+    // Telegram expects reaction emojis without the variation selector
+    let emoji = emoji.trim().replace('\u{fe0f}', "");
+    bot.set_message_reaction(ChatId(chat_id), message_id)
+        .reaction([ReactionType::Emoji { emoji }])
+        .await?;
+    Ok(())
+}
+
+pub async fn pin_message(
+    bot: &Bot,
+    chat_id: i64,
+    message_id: MessageId,
+) -> Result<(), teloxide::RequestError> {
+    bot.pin_chat_message(ChatId(chat_id), message_id).await?;
+    Ok(())
+}
+
+impl TelegramSink {
+    pub fn new(bot: Bot, config: &Config) -> Self {
+        Self {
+            bot,
+            chat_ids: config.chat_ids.clone(),
+            template: config.template.clone(),
+            parse_mode: config.parse_mode,
+            notifications: config.notifications.clone(),
+            edit_duplicates: config.edit_duplicates,
+            reply_threading: config.reply_threading,
+            inline_keyboard: config.inline_keyboard,
+            sent: Arc::new(Mutex::new(SentStore::default())),
+        }
+    }
+
+    fn render(
+        &self,
+        data: &MessageData,
+        gateways: &[GatewayReport],
+    ) -> Result<String, minijinja::Error> {
+        let env = crate::create_template_env(self.parse_mode);
+
+        // With several gateways, the signal data comes from the best one
+        let best = gateways.iter().max_by(|a, b| {
+            a.snr
+                .unwrap_or(f32::MIN)
+                .total_cmp(&b.snr.unwrap_or(f32::MIN))
+        });
+        let via = gateways
+            .iter()
+            .map(|g| g.via.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let ctx = context! {
+            from => data.from.clone(),
+            is_name_conflict => data.is_name_conflict,
+            via => via,
+            text => data.text.clone(),
+            snr => best.and_then(|g| g.snr),
+            rssi => best.and_then(|g| g.rssi),
+            hops_away => gateways.iter().filter_map(|g| g.hops_away).min(),
+            gateways => gateways.to_vec(),
+        };
+
+        env.render_str(&self.template, ctx)
+    }
+
+    // Buttons are only attached to messages from remote nodes
+    fn keyboard(&self, data: &MessageData) -> Option<InlineKeyboardMarkup> {
+        (self.inline_keyboard && data.from_id != 0).then(|| bot::node_keyboard(data.from_id))
+    }
+
+    pub async fn deliver(&self, data: &MessageData) {
+        if self.edit_duplicates && self.update_existing(data).await {
+            return;
+        }
+
+        let gateways = vec![GatewayReport::from(data)];
+        let rendered = match self.render(data, &gateways) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{}", fl!("failed-to-render", error = e.to_string()));
+                return;
+            }
+        };
+
+        // Telegram messages of the packet this one replies to, if it was forwarded
+        let replied = match data.reply_id {
+            Some(reply_id) if self.reply_threading => self
+                .sent
+                .lock()
+                .await
+                .packets
+                .get(&reply_id)
+                .map(|p| p.messages.clone())
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+
+        let mut messages = Vec::new();
+        for chat_id in &self.chat_ids {
+            let reply_to = replied
+                .iter()
+                .find(|(c, _)| c == chat_id)
+                .map(|(_, message_id)| *message_id);
+
+            if data.is_reaction
+                && let Some(message_id) = reply_to
+            {
+                match set_reaction(&self.bot, *chat_id, message_id, &data.text).await {
+                    Ok(()) => {
+                        debug!(
+                            "{}",
+                            fl!(
+                                "reaction-set",
+                                from = data.from.clone(),
+                                reaction = data.text.clone()
+                            )
+                        );
+                        continue;
+                    }
+                    Err(err) => {
+                        debug!("{}", fl!("reaction-fallback", error = err.to_string()));
+                    }
+                }
+            }
+
+            let silent = self.notifications.is_silent(*chat_id, data);
+            match send_message(
+                &self.bot,
+                *chat_id,
+                &rendered,
+                self.parse_mode.telegram(),
+                silent,
+                reply_to,
+                self.keyboard(data),
+            )
+            .await
+            {
+                Err(err) => {
+                    warn!(
+                        "{}\n{}",
+                        fl!("failed-to-send", error = err.to_string()),
+                        fl!("message-content", content = rendered.clone())
+                    );
+                }
+                Ok(message_id) => {
+                    messages.push((*chat_id, message_id));
+                    debug!(
+                        "{}",
+                        fl!(
+                            "forwarded-to-telegram",
+                            from = data.from.clone(),
+                            message = rendered.clone()
+                        )
+                    );
+                }
+            }
+        }
+
+        if !messages.is_empty() {
+            self.sent.lock().await.insert(
+                data.id,
+                SentPacket {
+                    data: data.clone(),
+                    gateways,
+                    messages,
+                },
+            );
+        }
+    }
+
+    // Returns true if the packet has already been forwarded
+    async fn update_existing(&self, data: &MessageData) -> bool {
+        // The lock isn't held while editing, so a slow edit doesn't hold up other deliveries
+        let (original, gateways, messages) = {
+            let mut sent = self.sent.lock().await;
+            let Some(packet) = sent.packets.get_mut(&data.id) else {
+                return false;
+            };
+
+            let report = GatewayReport::from(data);
+            match packet.gateways.iter_mut().find(|g| g.via == report.via) {
+                Some(existing)
+                    if report.snr.unwrap_or(f32::MIN) > existing.snr.unwrap_or(f32::MIN) =>
+                {
+                    *existing = report;
+                }
+                Some(_) => {
+                    debug!(
+                        "{}",
+                        fl!(
+                            "duplicate-not-edited",
+                            id = format!("0x{:08x}", data.id),
+                            via = data.via.clone()
+                        )
+                    );
+                    return true;
+                }
+                None => packet.gateways.push(report),
+            }
+            (
+                packet.data.clone(),
+                packet.gateways.clone(),
+                packet.messages.clone(),
+            )
+        };
+
+        let rendered = match self.render(&original, &gateways) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{}", fl!("failed-to-render", error = e.to_string()));
+                return true;
+            }
+        };
+
+        for (chat_id, message_id) in &messages {
+            match edit_message(
+                &self.bot,
+                *chat_id,
+                *message_id,
+                &rendered,
+                self.parse_mode.telegram(),
+                self.keyboard(&original),
+            )
+            .await
+            {
+                Err(err) => {
+                    warn!(
+                        "{}\n{}",
+                        fl!("failed-to-edit", error = err.to_string()),
+                        fl!("message-content", content = rendered.clone())
+                    );
+                }
+                Ok(()) => {
+                    debug!(
+                        "{}",
+                        fl!(
+                            "edited-in-telegram",
+                            id = format!("0x{:08x}", data.id),
+                            via = data.via.clone()
+                        )
+                    );
+                }
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(id: u32) -> SentPacket {
+        SentPacket {
+            data: MessageData {
+                id,
+                ..Default::default()
+            },
+            gateways: Vec::new(),
+            messages: vec![(1, MessageId(id as i32))],
+        }
+    }
+
+    #[test]
+    fn evicts_oldest_packets_at_capacity() {
+        let mut store = SentStore::default();
+        for id in 0..SENT_CAPACITY as u32 + 10 {
+            store.insert(id, packet(id));
+        }
+        assert_eq!(store.packets.len(), SENT_CAPACITY);
+        assert_eq!(store.order.len(), SENT_CAPACITY);
+        assert!(!store.packets.contains_key(&9));
+        assert!(store.packets.contains_key(&10));
+        assert!(store.packets.contains_key(&(SENT_CAPACITY as u32 + 9)));
+    }
+
+    #[test]
+    fn reinserting_a_packet_keeps_its_place() {
+        let mut store = SentStore::default();
+        store.insert(1, packet(1));
+        store.insert(2, packet(2));
+        store.insert(1, packet(1));
+        assert_eq!(store.order, [1, 2]);
+        for id in 3..SENT_CAPACITY as u32 + 2 {
+            store.insert(id, packet(id));
+        }
+        assert!(!store.packets.contains_key(&1));
+        assert!(store.packets.contains_key(&2));
+    }
+}
@@ -2,48 +2,266 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use anyhow::{Context, Result};
 use log::{debug, warn};
-use reqwest::{Client, header};
-use serde_json::to_string;
-
-use crate::{fl, MessageData};
-
-pub async fn send_message(client: &Client, url: &str, data: &MessageData) {
-    let json = match to_string(data) {
-        Ok(j) => j,
-        Err(e) => {
-            let error_msg: String = e.to_string();
-            warn!("{}", fl!("failed-to-render", error = error_msg));
-            return;
-        }
+use minijinja::{AutoEscape, Environment};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::events::Event;
+use crate::fl;
+use crate::http::{RetryPolicy, send_with_retry};
+use crate::payload::{CloudEvent, EnvelopeV2, PayloadFormat};
+
+pub const SIGNATURE_HEADER: &str = "X-EMtT-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-EMtT-Timestamp";
+
+const DEFAULT_CONTENT_TYPE: &str = "application/json";
+const CLOUDEVENTS_CONTENT_TYPE: &str = "application/cloudevents+json";
+
+// A `[[webhooks]]` entry of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    url: String,
+    method: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    content_type: Option<String>,
+    template: Option<String>,
+    secret: Option<String>,
+    format: Option<PayloadFormat>,
+}
+
+// Settings from the command line, shared by all webhooks unless overridden
+#[derive(Clone, Debug)]
+pub struct WebhookDefaults {
+    pub secret: Option<String>,
+    pub policy: RetryPolicy,
+    pub format: PayloadFormat,
+}
+
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    pub url: String,
+    method: Method,
+    headers: HeaderMap,
+    content_type: Option<String>,
+    template: Option<String>,
+    pub secret: Option<String>,
+    pub format: PayloadFormat,
+    pub policy: RetryPolicy,
+}
+
+// HMAC-SHA256 over "<timestamp>.<body>", as "sha256=<hex>"
+fn sign(secret: &str, timestamp: u64, body: &str) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(timestamp.to_string().as_bytes())?;
+    signer.update(b".")?;
+    signer.update(body.as_bytes())?;
+    let signature = signer.sign_to_vec()?;
+    let hex: String = signature.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("sha256={}", hex))
+}
+
+// Values in JSON bodies are written as JSON literals, so `{"text": {{ text }}}` stays valid
+fn create_body_env(content_type: &str) -> Environment<'static> {
+    let mut env = Environment::new();
+    let auto_escape = if content_type.contains("json") {
+        AutoEscape::Json
+    } else {
+        AutoEscape::None
     };
+    env.set_auto_escape_callback(move |_name| auto_escape);
+    env
+}
+
+impl Webhook {
+    // A plain JSON POST, as configured by `--webhook-url`
+    pub fn new(url: String, defaults: &WebhookDefaults) -> Self {
+        Self {
+            url,
+            method: Method::POST,
+            headers: HeaderMap::new(),
+            content_type: None,
+            template: None,
+            secret: defaults.secret.clone(),
+            format: defaults.format,
+            policy: defaults.policy,
+        }
+    }
 
-    let json_for_log = json.clone();
+    pub fn compile(config: &WebhookConfig, defaults: &WebhookDefaults) -> Result<Self> {
+        let method = match &config.method {
+            Some(m) => Method::from_bytes(m.to_uppercase().as_bytes())
+                .with_context(|| format!("Invalid HTTP method {:?} for {}", m, config.url))?,
+            None => Method::POST,
+        };
 
-    match client
-        .post(url)
-        .header(header::CONTENT_TYPE, "application/json")
-        .body(json)
-        .send()
-        .await
-    {
-        Ok(_) => {
-            debug!(
-                "{}",
-                fl!(
-                    "forwarded-to-webhook",
-                    from = data.from.clone(),  // Clone because `from` is String and macro may take by value
-                    message = json_for_log
-                )
-            );
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Invalid header name {:?} for {}", name, config.url))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid value of header {} for {}", name, config.url))?;
+            headers.insert(name, value);
+        }
+
+        let webhook = Self {
+            url: config.url.clone(),
+            method,
+            headers,
+            content_type: config.content_type.clone(),
+            template: config.template.clone(),
+            secret: config.secret.clone().or_else(|| defaults.secret.clone()),
+            format: config.format.unwrap_or(defaults.format),
+            policy: defaults.policy,
+        };
+
+        if let Some(template) = &webhook.template {
+            create_body_env(webhook.content_type())
+                .template_from_str(template)
+                .with_context(|| format!("Invalid body template for {}", config.url))?;
         }
-        Err(err) => {
-            let err_msg: String = err.to_string();
-            warn!(
-                "{}\n{}",
-                fl!("failed-to-send-webhook", error = err_msg),
-                fl!("message-content", content = json_for_log)
-            );
+
+        Ok(webhook)
+    }
+
+    fn content_type(&self) -> &str {
+        match (&self.content_type, self.format) {
+            (Some(content_type), _) => content_type,
+            (None, PayloadFormat::CloudEvents) => CLOUDEVENTS_CONTENT_TYPE,
+            (None, _) => DEFAULT_CONTENT_TYPE,
+        }
+    }
+
+    pub fn accepts(&self, event: &Event) -> bool {
+        self.format.accepts(event)
+    }
+
+    fn render<T: Serialize>(&self, payload: &T) -> Result<String> {
+        match &self.template {
+            Some(template) => {
+                Ok(create_body_env(self.content_type()).render_str(template, payload)?)
+            }
+            None => Ok(serde_json::to_string(payload)?),
+        }
+    }
+
+    // The body and any headers that go with it
+    fn render_body(&self, event: &Event) -> Result<(String, Vec<(&'static str, String)>)> {
+        match (self.format, event) {
+            (PayloadFormat::V1, Event::Text(data)) => Ok((self.render(data)?, Vec::new())),
+            (PayloadFormat::V2, Event::Text(data)) => {
+                Ok((self.render(&EnvelopeV2::text(data))?, Vec::new()))
+            }
+            (PayloadFormat::CloudEvents, _) => {
+                Ok((self.render(&CloudEvent::new(event)?)?, Vec::new()))
+            }
+            (PayloadFormat::CloudEventsBinary, _) => {
+                let cloud_event = CloudEvent::new(event)?;
+                Ok((
+                    self.render(&cloud_event.data)?,
+                    cloud_event.binary_headers(),
+                ))
+            }
+            _ => anyhow::bail!(
+                "{} events can't be sent as {}",
+                event.kind(),
+                self.format.name()
+            ),
         }
     }
+
+    pub async fn send(&self, client: &Client, event: &Event) {
+        let (body, event_headers) = match self.render_body(event) {
+            Ok(b) => b,
+            Err(e) => {
+                warn!("{}", fl!("failed-to-render", error = e.to_string()));
+                return;
+            }
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let signature = match &self.secret {
+            Some(secret) => match sign(secret, timestamp, &body) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    warn!("{}", fl!("failed-to-sign-webhook", error = e.to_string()));
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        match send_with_retry(&self.policy, || {
+            let mut req = client
+                .request(self.method.clone(), &self.url)
+                .header(header::CONTENT_TYPE, self.content_type())
+                .headers(self.headers.clone())
+                .body(body.clone());
+            for (name, value) in &event_headers {
+                req = req.header(*name, value);
+            }
+            if let Some(signature) = &signature {
+                req = req
+                    .header(TIMESTAMP_HEADER, timestamp)
+                    .header(SIGNATURE_HEADER, signature);
+            }
+            req
+        })
+        .await
+        {
+            Ok(_) => {
+                metrics::counter!("emtt_sink_sends_total", "sink" => "webhook", "result" => "success")
+                    .increment(1);
+                debug!(
+                    "{}",
+                    fl!(
+                        "forwarded-to-webhook",
+                        from = event.sender_name(),
+                        message = body.clone()
+                    )
+                );
+            }
+            Err(err) => {
+                metrics::counter!("emtt_sink_sends_total", "sink" => "webhook", "result" => "failure")
+                    .increment(1);
+                let err_msg: String = err.to_string();
+                warn!(
+                    "{}\n{}",
+                    fl!("failed-to-send-webhook", error = err_msg),
+                    fl!("message-content", content = body.clone())
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            sign("It's a Secret to Everybody", 1700000000, "Hello, World!").unwrap(),
+            "sha256=76c83fd0acdf22faed320674fe8e04d528cfe8a17905e720a9611e40677c03b7"
+        );
+        assert_eq!(
+            sign("key", 1700000000, r#"{"text":"hi"}"#).unwrap(),
+            "sha256=e0fb27bbd98eedf3b560379f4c21ed82331fa6f39b2c39ccf439a53c12cd1485"
+        );
+    }
 }